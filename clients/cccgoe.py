@@ -1,4 +1,5 @@
 import socket
+import struct
 import random
 from PIL import Image
 
@@ -16,10 +17,12 @@ def pixel(x, y, r, g, b, a=255):
         send(b"PX %d %d %02x%02x%02x%02x\n" % (x, y, r, g, b, a))
 
 
+def pixel_bin(x, y, r, g, b, a=255):
+    send(b"PB" + struct.pack("<HHBBBB", x, y, r, g, b, a))
+
+
 def rect(x, y, w, h, r, g, b):
-    for i in range(x, x + w):
-        for j in range(y, y + h):
-            pixel(i, j, r, g, b)
+    send(b"RECT %d %d %d %d %02x%02x%02x\n" % (x, y, w, h, r, g, b))
 
 
 
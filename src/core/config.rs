@@ -1,15 +1,743 @@
-use serde::Deserialize;
+use std::{collections::HashMap, fmt::Display, fs, io, path::Path};
+
+use serde::{de::DeserializeOwned, Deserialize};
 
 use super::image::Coord;
 
 #[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub num_io_threads: usize,
     pub image_width: Coord,
     pub image_height: Coord,
 
     pub listen_addr: String,
+    pub accept_mode: AcceptMode,
+    /// Which IO thread gets a connection in handoff mode
+    pub placement: PlacementStrategy,
+    /// Also accept Pixelflut over WebSocket (for browser clients) on this address
+    pub websocket_addr: Option<String>,
+    /// Also accept Pixelflut datagrams on this address, received by all IO threads from one shared socket
+    pub udp_addr: Option<String>,
+    /// Also accept Pixelflut on a Unix domain socket at this path (a stale socket file is replaced)
+    pub unix_socket: Option<String>,
+    /// Execute the Pixelflut commands in this file ('-' for stdin) on startup; responses go to stdout
+    pub input: Option<String>,
+    /// Serve Prometheus metrics over HTTP on this address
+    pub metrics_addr: Option<String>,
 
+    /// What runs on the main thread
+    pub frontend: Frontend,
     pub gst_window: bool,
+    /// Frame rate of the gstreamer pipeline; unchanged frames are repeated without copying the image
+    pub gst_fps: u32,
     pub record_to_file: Option<String>,
-}
\ No newline at end of file
+    pub recording: RecordingConfig,
+    /// Additional outputs as gstreamer pipeline descriptions (e.g. "x264enc ! flvmux ! rtmpsink location=..."),
+    /// each fed the raw video through its own queue
+    pub gst_branches: Vec<String>,
+    /// How often the winit frontend redraws the window
+    pub winit_fps: u32,
+
+    pub limits: LimitsConfig,
+    pub snapshot: SnapshotConfig,
+    pub viewer: ViewerConfig,
+    pub journal: JournalConfig,
+    pub overlay: OverlayConfig,
+    pub leaderboard: LeaderboardConfig,
+}
+
+/// How accepted connections are distributed between the IO threads
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptMode {
+    /// IO Worker 0 accepts all connections and hands them off to a thread
+    #[default]
+    Handoff,
+    /// Every IO thread accepts on its own SO_REUSEPORT listener
+    ReusePort,
+}
+
+/// The frontend that displays or records the canvas
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Frontend {
+    /// The gstreamer pipeline, with a window (`gst_window`) and/or a recording (`record_to_file`)
+    Gstreamer,
+    /// A window drawn with winit and softbuffer, at `winit_fps`
+    Winit,
+    /// Headless: only serve clients
+    None,
+}
+
+impl Default for Frontend {
+    fn default() -> Self {
+        if cfg!(feature = "gstreamer") {
+            Frontend::Gstreamer
+        } else {
+            Frontend::None
+        }
+    }
+}
+
+/// Strategy for choosing the IO thread of a handed off connection
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PlacementStrategy {
+    #[default]
+    Random,
+    RoundRobin,
+    /// Fewest open connections
+    LeastConnections,
+    /// Fewest bytes received per second, sampled every second
+    LeastBytes,
+}
+
+/// What to do with pixels that exceed a client's budget
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum OverLimit {
+    /// Stop reading from the client until the budget has refilled (backpressure)
+    #[default]
+    Delay,
+    /// Silently discard the pixel
+    Drop,
+    /// Discard the pixel and reply with an error line
+    Error,
+}
+
+/// Per source address limits, shared by all connections from that address
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections_per_ip: Option<usize>,
+    /// Token bucket refill rate
+    pub pixels_per_second: Option<u32>,
+    /// Token bucket size (defaults to one second's worth of pixels)
+    pub pixel_burst: Option<u32>,
+    pub over_limit: OverLimit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            num_io_threads: 4,
+            image_width: 1280,
+            image_height: 720,
+            listen_addr: "127.0.0.1:4000".to_owned(),
+            accept_mode: AcceptMode::Handoff,
+            placement: PlacementStrategy::Random,
+            websocket_addr: None,
+            udp_addr: None,
+            unix_socket: None,
+            input: None,
+            metrics_addr: None,
+            frontend: Frontend::default(),
+            gst_window: true,
+            gst_fps: 60,
+            record_to_file: None,
+            recording: RecordingConfig::default(),
+            gst_branches: Vec::new(),
+            winit_fps: 60,
+            limits: LimitsConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            viewer: ViewerConfig::default(),
+            journal: JournalConfig::default(),
+            overlay: OverlayConfig::default(),
+            leaderboard: LeaderboardConfig::default(),
+        }
+    }
+}
+
+/// Video encoders for the recording, by codec. AV1 and FFV1 need a matroska (or webm, for AV1) container.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoder {
+    /// H.264 on VA-API hardware (Intel/AMD)
+    Vah264,
+    X264,
+    OpenH264,
+    Vp8,
+    SvtAv1,
+    Rav1e,
+    /// Lossless
+    Ffv1,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Use the first of these encoders that is installed and fits the container of `record_to_file`
+    pub encoders: Vec<Encoder>,
+    pub bitrate_kbps: Option<u32>,
+    /// Speed/quality tradeoff per encoder, e.g. `{ x264 = "ultrafast", vah264 = "7" }`. Only the one of the encoder
+    /// that is picked applies.
+    pub presets: HashMap<Encoder, String>,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            encoders: vec![
+                Encoder::Vah264,
+                Encoder::X264,
+                Encoder::OpenH264,
+                Encoder::Vp8,
+            ],
+            bitrate_kbps: None,
+            presets: HashMap::new(),
+        }
+    }
+}
+
+/// How to fit an initial canvas image that has different dimensions than the canvas
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CanvasFit {
+    /// Keep the image at the top-left, cropping or padding with black
+    #[default]
+    Crop,
+    /// Stretch the image to the canvas size
+    Scale,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Where to periodically write the canvas (PNG or QOI, depending on the extension); disabled if unset
+    pub path: Option<String>,
+    pub interval_secs: u64,
+    /// Restore the canvas from `path` on startup, if it exists
+    pub restore: bool,
+    /// Image to use as the initial canvas instead (any supported format)
+    pub initial_canvas: Option<String>,
+    pub fit: CanvasFit,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval_secs: 60,
+            restore: true,
+            initial_canvas: None,
+            fit: CanvasFit::Crop,
+        }
+    }
+}
+
+/// Live view of the canvas for browsers, as an MJPEG stream
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViewerConfig {
+    /// Serve the viewer over HTTP on this address; disabled if unset
+    pub addr: Option<String>,
+    pub fps: u32,
+    pub max_viewers: usize,
+    /// JPEG quality (1-100)
+    pub quality: u8,
+}
+
+impl Default for ViewerConfig {
+    fn default() -> Self {
+        Self {
+            addr: None,
+            fps: 10,
+            max_viewers: 16,
+            quality: 80,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalConfig {
+    /// Append every accepted mutating command to this file (see the `replay` subcommand); disabled if unset
+    pub path: Option<String>,
+}
+
+/// What anonymous clients get to see of the leaderboard (the metrics endpoint always shows full addresses)
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaderboardConfig {
+    /// Answer the LEADERBOARD command
+    pub command: bool,
+    /// Show only the /24 (IPv4) or /64 (IPv6) of each address, in the LEADERBOARD command and in the overlay
+    pub mask_addresses: bool,
+}
+
+impl Default for LeaderboardConfig {
+    fn default() -> Self {
+        Self {
+            command: true,
+            mask_addresses: false,
+        }
+    }
+}
+
+/// Statistics shown on top of the gstreamer output and in the winit window
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverlayConfig {
+    pub enabled: bool,
+    /// Placeholders: {clients}, {pixels_per_second}, {pixels}, {leaderboard}, {listen_addr}, {width}, {height}
+    pub text: String,
+    pub position: OverlayPosition,
+}
+
+impl Default for OverlayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            text: "{listen_addr} | {width}x{height} | {clients} clients | {pixels_per_second} px/s | {pixels} px\n{leaderboard}"
+                .to_owned(),
+            position: OverlayPosition::TopLeft,
+        }
+    }
+}
+
+/// The corner of the canvas the overlay is shown in
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl OverlayPosition {
+    pub fn is_top(self) -> bool {
+        matches!(self, OverlayPosition::TopLeft | OverlayPosition::TopRight)
+    }
+
+    pub fn is_left(self) -> bool {
+        matches!(self, OverlayPosition::TopLeft | OverlayPosition::BottomLeft)
+    }
+}
+
+/// Options of the `replay` subcommand
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub journal: Option<String>,
+    /// Reconstruct the canvas this many seconds after the start of the journal, instead of at its end
+    pub at: Option<f64>,
+    /// Write the reconstructed canvas to this image file
+    pub output: Option<String>,
+    /// Re-render the whole session to this video file instead
+    pub video: Option<String>,
+    /// Playback speed of the video, relative to real time
+    pub speed: f64,
+    /// Also show the video while rendering it
+    pub window: bool,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            journal: None,
+            at: None,
+            output: None,
+            video: None,
+            speed: 1.0,
+            window: false,
+        }
+    }
+}
+
+impl ReplayConfig {
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<ReplayConfig, ConfigError> {
+        let config: ReplayConfig = parse_args(args)?;
+        if config.journal.is_none() {
+            return Err(ConfigError::Usage("replay requires --journal".to_owned()));
+        }
+        if config.output.is_some() == config.video.is_some() {
+            return Err(ConfigError::Usage(
+                "replay requires exactly one of --output and --video".to_owned(),
+            ));
+        }
+        if config.at.is_some() && config.video.is_some() {
+            return Err(ConfigError::Usage(
+                "--at only applies to --output".to_owned(),
+            ));
+        }
+        if config.at.is_some_and(|at| !at.is_finite() || at < 0.0) {
+            return Err(ConfigError::Invalid("at must not be negative"));
+        }
+        if !config.speed.is_finite() || config.speed <= 0.0 {
+            return Err(ConfigError::Invalid("speed must be positive"));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was passed; not really an error, but we should stop here.
+    Help,
+    Usage(String),
+    Read(String, io::Error),
+    Parse(toml::de::Error),
+    Invalid(&'static str),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Help => f.write_str(USAGE),
+            ConfigError::Usage(msg) => write!(f, "{msg}\n\n{USAGE}"),
+            ConfigError::Read(path, err) => write!(f, "failed to read config file '{path}': {err}"),
+            ConfigError::Parse(err) => write!(f, "invalid configuration: {err}"),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+const USAGE: &str = "Usage: pixelflut_monoio [--config <path.toml>] [--<key> <value>]...
+       pixelflut_monoio replay --journal <path> [--at <seconds>] (--output <image> | --video <file> [--speed <factor>] [--window])
+
+Every configuration key can be overridden on the command line, with '_' written as '-'
+and nested keys separated by '.' (e.g. --num-io-threads 8 --listen-addr 0.0.0.0:1337).
+Values are parsed as TOML, and fall back to a plain string where the key does not accept the parsed value
+(so --listen-addr 4000 is the string \"4000\"). Use --<key>=<value> for values starting with '--'.
+A flag without a value is `true`.";
+
+/// Insert `value` at the dotted `key` path, creating intermediate tables as needed.
+fn set_dotted(table: &mut toml::Table, key: &str, value: toml::Value) -> Result<(), ConfigError> {
+    let mut table = table;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            table.insert(part.to_owned(), value);
+            break;
+        }
+        let entry = table
+            .entry(part.to_owned())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        let toml::Value::Table(inner) = entry else {
+            return Err(ConfigError::Usage(format!(
+                "'{part}' in --{key} is not a table"
+            )));
+        };
+        table = inner;
+    }
+    Ok(())
+}
+
+fn parse_cli_value(raw: &str) -> toml::Value {
+    match format!("v = {raw}").parse::<toml::Table>() {
+        Ok(mut t) => t.remove("v").unwrap(),
+        Err(_) => toml::Value::String(raw.to_owned()),
+    }
+}
+
+/// A `--key value` pair from the command line
+struct Override {
+    key: String,
+    value: toml::Value,
+    /// The value as written, if it parsed as something other than a string
+    raw: Option<String>,
+}
+
+/// Deserialize the configuration with the overrides applied
+fn apply_overrides<T: DeserializeOwned>(
+    table: &toml::Table,
+    overrides: &[Override],
+) -> Result<T, ConfigError> {
+    let mut table = table.clone();
+    for Override { key, value, .. } in overrides {
+        set_dotted(&mut table, key, value.clone())?;
+    }
+    toml::Value::Table(table)
+        .try_into()
+        .map_err(ConfigError::Parse)
+}
+
+/// Deserialize `T` from the command line (without the program name). See [USAGE] for the syntax.
+fn parse_args<T: DeserializeOwned, I: IntoIterator<Item = String>>(
+    args: I,
+) -> Result<T, ConfigError> {
+    let mut table = toml::Table::new();
+    let mut overrides = Vec::new();
+
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::Usage(format!("unexpected argument '{arg}'")));
+        };
+        if flag == "help" {
+            return Err(ConfigError::Help);
+        }
+
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_owned())),
+            None => match args.peek() {
+                Some(next) if !next.starts_with("--") => (flag, args.next()),
+                _ => (flag, None),
+            },
+        };
+        if flag == "config" {
+            let Some(path) = value else {
+                return Err(ConfigError::Usage("--config requires a path".to_owned()));
+            };
+            let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path, e))?;
+            table = contents.parse().map_err(ConfigError::Parse)?;
+        } else {
+            let (value, raw) = match value {
+                None => (toml::Value::Boolean(true), None),
+                Some(raw) => match parse_cli_value(&raw) {
+                    toml::Value::String(value) => (toml::Value::String(value), None),
+                    value => (value, Some(raw)),
+                },
+            };
+            overrides.push(Override {
+                key: flag.replace('-', "_"),
+                value,
+                raw,
+            });
+        }
+    }
+
+    // Apply overrides after the file has been read, so that --config can appear anywhere
+    loop {
+        let err = match apply_overrides(&table, &overrides) {
+            Err(ConfigError::Parse(err)) => err,
+            result => return result,
+        };
+        // A value that parsed as e.g. a number may be meant for a string key, so retry the one the error is about
+        // as a string
+        let rejected = overrides
+            .iter_mut()
+            .find(|o| o.raw.is_some() && err.to_string().ends_with(&format!("in `{}`\n", o.key)));
+        let Some(rejected) = rejected else {
+            return Err(ConfigError::Parse(err));
+        };
+        rejected.value = toml::Value::String(rejected.raw.take().unwrap());
+    }
+}
+
+impl Config {
+    /// Build the configuration from the command line (without the program name).
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let config: Config = parse_args(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.num_io_threads == 0 {
+            return Err(ConfigError::Invalid("num_io_threads must be at least 1"));
+        }
+        if self.image_width == 0 || self.image_height == 0 {
+            return Err(ConfigError::Invalid(
+                "image_width and image_height must be non-zero",
+            ));
+        }
+        if self.frontend == Frontend::Gstreamer && !cfg!(feature = "gstreamer") {
+            return Err(ConfigError::Invalid(
+                "frontend \"gstreamer\" requires building with the gstreamer feature",
+            ));
+        }
+        if self.frontend == Frontend::Winit && !cfg!(feature = "winit") {
+            return Err(ConfigError::Invalid(
+                "frontend \"winit\" requires building with the winit feature",
+            ));
+        }
+        if self.gst_fps == 0 || self.winit_fps == 0 {
+            return Err(ConfigError::Invalid(
+                "gst_fps and winit_fps must be non-zero",
+            ));
+        }
+        if self.recording.encoders.is_empty() {
+            return Err(ConfigError::Invalid(
+                "recording.encoders must not be empty",
+            ));
+        }
+        if self.listen_addr.is_empty() {
+            return Err(ConfigError::Invalid("listen_addr must not be empty"));
+        }
+        if self.limits.max_connections_per_ip == Some(0) {
+            return Err(ConfigError::Invalid(
+                "limits.max_connections_per_ip must be at least 1",
+            ));
+        }
+        if self.limits.pixels_per_second == Some(0) || self.limits.pixel_burst == Some(0) {
+            return Err(ConfigError::Invalid(
+                "limits.pixels_per_second and limits.pixel_burst must be non-zero",
+            ));
+        }
+        if self.snapshot.interval_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "snapshot.interval_secs must be non-zero",
+            ));
+        }
+        if let Some(path) = &self.snapshot.path {
+            // Checked here, since snapshot_loop would otherwise only fail on every interval
+            let supported = Path::new(path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("qoi")
+                });
+            if !supported {
+                return Err(ConfigError::Invalid(
+                    "snapshot.path must end in .png or .qoi",
+                ));
+            }
+        }
+        if self.viewer.fps == 0 {
+            return Err(ConfigError::Invalid("viewer.fps must be non-zero"));
+        }
+        if !(1..=100).contains(&self.viewer.quality) {
+            return Err(ConfigError::Invalid(
+                "viewer.quality must be between 1 and 100",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError, Encoder, Frontend, OverLimit, OverlayPosition, ReplayConfig};
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn test_cli_overrides() {
+        let config = Config::from_args(args(
+            "--num-io-threads 8 --listen-addr 0.0.0.0:1337 --record-to-file out.mkv",
+        ))
+        .unwrap();
+        assert_eq!(config.num_io_threads, 8);
+        assert_eq!(config.listen_addr, "0.0.0.0:1337");
+        assert_eq!(config.record_to_file.as_deref(), Some("out.mkv"));
+        assert_eq!(config.image_width, Config::default().image_width);
+
+        let config = Config::from_args(args("--limits.over-limit error")).unwrap();
+        assert_eq!(config.limits.over_limit, OverLimit::Error);
+
+        let config = Config::from_args(args("--frontend none")).unwrap();
+        assert_eq!(config.frontend, Frontend::None);
+
+        let config = Config::from_args(args(
+            "--overlay.enabled true --overlay.position bottom_right",
+        ))
+        .unwrap();
+        assert!(config.overlay.enabled);
+        assert_eq!(config.overlay.position, OverlayPosition::BottomRight);
+
+        let config = Config::from_args(args("--leaderboard.command false")).unwrap();
+        assert!(!config.leaderboard.command);
+        assert!(!config.leaderboard.mask_addresses);
+
+        // Values for string keys stay strings, even if they look like something else
+        let config = Config::from_args(args(
+            "--num-io-threads 4 --listen-addr 4 --record-to-file 2024 --image-width 640",
+        ))
+        .unwrap();
+        assert_eq!(config.num_io_threads, 4);
+        assert_eq!(config.listen_addr, "4");
+        assert_eq!(config.record_to_file.as_deref(), Some("2024"));
+        assert_eq!(config.image_width, 640);
+        assert!(matches!(
+            Config::from_args(args("--num-io-threads four")),
+            Err(ConfigError::Parse(_))
+        ));
+
+        let config =
+            Config::from_args(args("--record-to-file=--odd-name.mkv --frontend=none")).unwrap();
+        assert_eq!(config.record_to_file.as_deref(), Some("--odd-name.mkv"));
+        assert_eq!(config.frontend, Frontend::None);
+    }
+
+    #[test]
+    fn test_toml_defaults() {
+        let config: Config = toml::from_str("image_width = 640").unwrap();
+        assert_eq!(config.image_width, 640);
+        assert_eq!(config.image_height, Config::default().image_height);
+        assert!(toml::from_str::<Config>("image_widht = 640").is_err());
+
+        let config: Config =
+            toml::from_str(r#"gst_branches = ["x264enc ! mpegtsmux ! srtsink uri=srt://:8888"]"#)
+                .unwrap();
+        assert_eq!(config.gst_branches.len(), 1);
+
+        let config = Config::from_args(args("--recording.presets.x264 ultrafast")).unwrap();
+        assert_eq!(config.recording.presets[&Encoder::X264], "ultrafast");
+        assert!(toml::from_str::<Config>("[recording.presets]\nx265 = \"fast\"").is_err());
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(matches!(
+            Config::from_args(args("--num-io-threads 0")),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--image-height 0")),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--gst-fps 0")),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_args(args("--gst-window maybe")),
+            Err(ConfigError::Parse(_))
+        ));
+        assert_eq!(
+            Config::from_args(args("--frontend gstreamer")).is_ok(),
+            cfg!(feature = "gstreamer")
+        );
+        assert_eq!(
+            Config::from_args(args("--frontend winit")).is_ok(),
+            cfg!(feature = "winit")
+        );
+        assert!(matches!(
+            Config::from_args(args("--winit-fps 0")),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(Config::from_args(args("--snapshot.path canvas.QOI")).is_ok());
+        for path in ["canvas.jpg", "canvas"] {
+            assert!(matches!(
+                Config::from_args(args(&format!("--snapshot.path {path}"))),
+                Err(ConfigError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_replay_args() {
+        let config =
+            ReplayConfig::from_args(args("--journal j.bin --at 2 --output out.png")).unwrap();
+        assert_eq!(config.journal.as_deref(), Some("j.bin"));
+        assert_eq!(config.at, Some(2.0));
+
+        let config =
+            ReplayConfig::from_args(args("--journal j.bin --video out.mkv --speed 10")).unwrap();
+        assert_eq!(config.speed, 10.0);
+        assert!(!config.window);
+
+        for invalid in [
+            "--output out.png",
+            "--journal j.bin",
+            "--journal j.bin --output out.png --video out.mkv",
+            "--journal j.bin --video out.mkv --at 1",
+        ] {
+            assert!(matches!(
+                ReplayConfig::from_args(args(invalid)),
+                Err(ConfigError::Usage(_))
+            ));
+        }
+        assert!(matches!(
+            ReplayConfig::from_args(args("--journal j.bin --video out.mkv --speed 0")),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+}
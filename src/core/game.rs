@@ -1,39 +1,108 @@
+use std::{collections::HashMap, sync::Mutex};
+
 use super::{
     config::Config,
     image::PixelflutImage,
+    journal::Journal,
+    leaderboard::Leaderboard,
+    limits::ClientLimits,
+    metrics::{GlobalMetrics, WorkerMetrics},
+    shutdown::Shutdown,
+    snapshot::{initial_canvas, SnapshotError},
     state::{PixelflutGlobalConfig, PixelflutGlobalState, PixelflutThreadState},
+    viewer::ViewerFeed,
 };
 
 pub struct PixelflutGame {
     state: PixelflutGlobalState,
+    /// One per IO thread, followed by one for the input (if configured)
     workers: Vec<PixelflutThreadState>,
+    num_io_threads: usize,
 }
 
 impl PixelflutGame {
-    pub fn new(config: &Config) -> &'static PixelflutGame {
+    pub fn new(config: &Config) -> Result<&'static PixelflutGame, SnapshotError> {
         let global_config = PixelflutGlobalConfig {
             width: config.image_width,
             height: config.image_height,
         };
 
+        let image = initial_canvas(&config.snapshot, config.image_width, config.image_height)?
+            .unwrap_or_else(|| PixelflutImage::new_with(config.image_width, config.image_height));
+
         let game = Box::leak(Box::new(PixelflutGame {
             state: PixelflutGlobalState{
-                image: PixelflutImage::new_with(config.image_width, config.image_height)
+                image,
+                limits: ClientLimits::new(&config.limits),
+                shutdown: Shutdown::new(),
+                io_stopped: Shutdown::new(),
+                metrics: GlobalMetrics::default(),
+                viewers: ViewerFeed::new(config.viewer.max_viewers),
+                journal: Journal::new(config.journal.path.is_some()),
+                leaderboard: Leaderboard::new(&config.leaderboard),
             },
             workers: Vec::new(),
+            num_io_threads: config.num_io_threads,
         }));
-        game.workers.resize_with(config.num_io_threads, || PixelflutThreadState {
+        let num_workers = config.num_io_threads + usize::from(config.input.is_some());
+        game.workers.resize_with(num_workers, || PixelflutThreadState {
             global_config,
             global_state: &game.state,
+            metrics: WorkerMetrics::default(),
+            journal_buffer: Mutex::new(Vec::new()),
+            pixels_by_ip: Mutex::new(HashMap::new()),
         });
 
-        game
+        Ok(game)
     }
 
     pub fn image(&self) -> &PixelflutImage {
         &self.state.image
     }
 
+    pub fn limits(&self) -> &ClientLimits {
+        &self.state.limits
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.state.shutdown
+    }
+
+    pub fn io_stopped(&self) -> &Shutdown {
+        &self.state.io_stopped
+    }
+
+    pub fn metrics(&self) -> &GlobalMetrics {
+        &self.state.metrics
+    }
+
+    pub fn viewers(&self) -> &ViewerFeed {
+        &self.state.viewers
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.state.journal
+    }
+
+    pub fn leaderboard(&self) -> &Leaderboard {
+        &self.state.leaderboard
+    }
+
+    /// The states of all threads that serve clients, including the input's
+    pub fn workers(&self) -> &[PixelflutThreadState] {
+        &self.workers
+    }
+
+    /// The states of the IO threads, which connections can be handed to
+    pub fn io_workers(&self) -> &[PixelflutThreadState] {
+        &self.workers[..self.num_io_threads]
+    }
+
+    /// The state of the input thread, if `input` is configured
+    pub fn input_worker(&'static self) -> Option<&'static PixelflutThreadState> {
+        self.workers.get(self.num_io_threads)
+    }
+
     pub fn for_worker(&'static self, id: usize) -> &'static PixelflutThreadState {
         &self.workers[id]
     }
@@ -1,22 +1,48 @@
+use bit_set::BitSet;
 use core::slice::{self};
 use std::{
     ptr,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
 };
 
 #[repr(align(4))]
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
 pub struct RGBAPixel([u8; 4]);
 
 impl RGBAPixel {
     pub fn new_rgb(r: u8, g: u8, b: u8) -> Self {
         // FIXME: little-endian assumption
-        Self([r, g, b, 0])
+        Self([r, g, b, 0xFF])
     }
 
-    // FIXME: proper alpha blending
     pub fn new_rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
-        Self([r, g, b, 0])
+        Self([r, g, b, a])
+    }
+
+    /// Returns `[r, g, b, a]`
+    pub fn channels(&self) -> [u8; 4] {
+        self.0
+    }
+
+    pub fn alpha(&self) -> u8 {
+        self.0[3]
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.alpha() == 0xFF
+    }
+
+    /// Source-over compositing of `self` onto `dest`. The canvas is always opaque, so the result is too.
+    pub fn blend_over(&self, dest: RGBAPixel) -> RGBAPixel {
+        let a = self.alpha() as u32;
+        let mix =
+            |src: u8, dst: u8| ((src as u32 * a + dst as u32 * (0xFF - a) + 0x7F) / 0xFF) as u8;
+        Self([
+            mix(self.0[0], dest.0[0]),
+            mix(self.0[1], dest.0[1]),
+            mix(self.0[2], dest.0[2]),
+            0xFF,
+        ])
     }
 
     // FIXME: decide on representation
@@ -31,22 +57,60 @@ impl RGBAPixel {
 
 pub type Coord = u32;
 
+/// Width and height of the tiles tracked by [PixelflutImage::take_dirty_tiles]
+pub const TILE_SIZE: Coord = 32;
+
 pub struct PixelflutImage {
     pub height: Coord,
     pub width: Coord,
 
     pixel_data: Box<[AtomicU32]>,
+    /// Odd while there are writes that no consumer has seen yet, see [PixelflutImage::generation]
+    generation: AtomicU64,
+    /// One bit per tile, in row-major order, set when a pixel of the tile is written
+    dirty_tiles: Box<[AtomicU64]>,
+}
+
+/// Tile bitmap for an image of the given size, with all tiles dirty
+fn all_tiles_dirty(width: Coord, height: Coord) -> Box<[AtomicU64]> {
+    let tiles = (width.div_ceil(TILE_SIZE) as usize) * (height.div_ceil(TILE_SIZE) as usize);
+    (0..tiles.div_ceil(64))
+        .map(|word| {
+            let bits = (tiles - word * 64).min(64);
+            AtomicU64::new(u64::MAX >> (64 - bits))
+        })
+        .collect()
 }
 
 impl PixelflutImage {
     pub fn new_with(width: Coord, height: Coord) -> Self {
         let total = (height as usize) * (width as usize);
         let mut pixel_data = Vec::new();
-        pixel_data.resize_with(total, || AtomicU32::new(0));
+        let black = RGBAPixel::new_rgb(0, 0, 0).into_rgba();
+        pixel_data.resize_with(total, || AtomicU32::new(black));
         PixelflutImage {
             height,
             width,
             pixel_data: pixel_data.into_boxed_slice(),
+            generation: AtomicU64::new(0),
+            dirty_tiles: all_tiles_dirty(width, height),
+        }
+    }
+
+    /// Create an image from RGBA8 data, as produced by [PixelflutImage::scanout]
+    pub fn new_from_rgba(width: Coord, height: Coord, data: &[u8]) -> Self {
+        let total = (height as usize) * (width as usize);
+        assert_eq!(data.len(), total * size_of::<AtomicU32>());
+        let pixel_data = data
+            .chunks_exact(4)
+            .map(|px| AtomicU32::new(u32::from_le_bytes(px.try_into().unwrap())))
+            .collect();
+        PixelflutImage {
+            height,
+            width,
+            pixel_data,
+            generation: AtomicU64::new(0),
+            dirty_tiles: all_tiles_dirty(width, height),
         }
     }
 
@@ -59,10 +123,153 @@ impl PixelflutImage {
         (py as usize) * (self.width as usize) + (px as usize)
     }
 
-    // FIMXE: the alpha semantics are completely borked (TBD: use CAS)
     pub fn set_pixel(&self, px: Coord, py: Coord, pixel: RGBAPixel) {
         let i = self.index(px, py);
         self.pixel_data[i].store(pixel.into_rgba(), Ordering::Relaxed);
+        self.mark_dirty(px, py, 1, 1);
+    }
+
+    /// Mark the rectangle at (px, py) of size w x h as written
+    fn mark_dirty(&self, px: Coord, py: Coord, w: Coord, h: Coord) {
+        let tiles_x = self.tiles_x();
+        for ty in py / TILE_SIZE..=(py + h - 1) / TILE_SIZE {
+            for tx in px / TILE_SIZE..=(px + w - 1) / TILE_SIZE {
+                let tile = (ty * tiles_x + tx) as usize;
+                let bit = 1 << (tile % 64);
+                let word = &self.dirty_tiles[tile / 64];
+                if word.load(Ordering::Relaxed) & bit == 0 {
+                    word.fetch_or(bit, Ordering::Release);
+                }
+            }
+        }
+        // Only the first write after a consumer looked needs to touch the shared cache lines
+        if self.generation.load(Ordering::Relaxed) & 1 == 0 {
+            self.generation.fetch_or(1, Ordering::Release);
+        }
+    }
+
+    /// A counter that changes whenever the image was written since the last call, so consumers can skip scanning
+    /// out an unchanged image. This is best-effort: a write racing with this call may only be reflected by a
+    /// later one, so consumers should still rescan now and then.
+    pub fn generation(&self) -> u64 {
+        let mut generation = self.generation.load(Ordering::Acquire);
+        while generation & 1 == 1 {
+            match self.generation.compare_exchange_weak(
+                generation,
+                generation + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return generation + 1,
+                Err(current) => generation = current,
+            }
+        }
+        generation
+    }
+
+    /// Number of tile columns
+    pub fn tiles_x(&self) -> Coord {
+        self.width.div_ceil(TILE_SIZE)
+    }
+
+    /// Number of tile rows
+    pub fn tiles_y(&self) -> Coord {
+        self.height.div_ceil(TILE_SIZE)
+    }
+
+    /// The rectangle (x, y, w, h) covered by a tile, clipped to the image
+    pub fn tile_rect(&self, tile: usize) -> (Coord, Coord, Coord, Coord) {
+        let x = (tile as Coord % self.tiles_x()) * TILE_SIZE;
+        let y = (tile as Coord / self.tiles_x()) * TILE_SIZE;
+        assert!(self.bounds_check(x, y));
+        (
+            x,
+            y,
+            TILE_SIZE.min(self.width - x),
+            TILE_SIZE.min(self.height - y),
+        )
+    }
+
+    /// The tiles (indexed row-major, see [PixelflutImage::tile_rect]) written since the previous call, or all tiles
+    /// on the first call. This never blocks writers. The marks are shared, so there should only be one consumer;
+    /// others can use [PixelflutImage::generation]. Like that, a write racing with this call may show up late.
+    pub fn take_dirty_tiles(&self) -> BitSet {
+        let mut tiles = BitSet::with_capacity(self.dirty_tiles.len() * 64);
+        for (i, word) in self.dirty_tiles.iter().enumerate() {
+            if word.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            let mut bits = word.swap(0, Ordering::Acquire);
+            while bits != 0 {
+                tiles.insert(i * 64 + bits.trailing_zeros() as usize);
+                bits &= bits - 1;
+            }
+        }
+        tiles
+    }
+
+    /// Like [PixelflutImage::set_pixel], but composites `pixel` over the current color according to its alpha.
+    /// Concurrent blends onto the same pixel never lose updates, since we retry with CAS.
+    pub fn blend_pixel(&self, px: Coord, py: Coord, pixel: RGBAPixel) {
+        let i = self.index(px, py);
+        if pixel.alpha() == 0 {
+            return;
+        }
+        let _ = self.pixel_data[i].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+            Some(pixel.blend_over(RGBAPixel::from_rgba(current)).into_rgba())
+        });
+        self.mark_dirty(px, py, 1, 1);
+    }
+
+    /// Set an opaque pixel, or blend a translucent one, as the PX command does
+    pub fn draw_pixel(&self, px: Coord, py: Coord, pixel: RGBAPixel) {
+        if pixel.is_opaque() {
+            self.set_pixel(px, py, pixel);
+        } else {
+            self.blend_pixel(px, py, pixel);
+        }
+    }
+
+    /// Clip the rectangle at (px, py) of size w x h to the image. Returns None if nothing remains.
+    pub fn clip_rect(
+        &self,
+        px: Coord,
+        py: Coord,
+        w: Coord,
+        h: Coord,
+    ) -> Option<(Coord, Coord, Coord, Coord)> {
+        if !self.bounds_check(px, py) || w == 0 || h == 0 {
+            return None;
+        }
+        let w = w.min(self.width - px);
+        let h = h.min(self.height - py);
+        Some((px, py, w, h))
+    }
+
+    /// Fill a rectangle, which must be within bounds (see [PixelflutImage::clip_rect]).
+    /// Translucent colors are blended like [PixelflutImage::blend_pixel].
+    pub fn fill_rect(&self, px: Coord, py: Coord, w: Coord, h: Coord, pixel: RGBAPixel) {
+        assert!(w > 0 && h > 0);
+        assert!(self.bounds_check(px + w - 1, py + h - 1));
+        for y in py..py + h {
+            let start = self.index(px, y);
+            let row = &self.pixel_data[start..start + w as usize];
+            if pixel.is_opaque() {
+                let rgba = pixel.into_rgba();
+                for dest in row {
+                    dest.store(rgba, Ordering::Relaxed);
+                }
+            } else if pixel.alpha() != 0 {
+                for dest in row {
+                    let _ = dest.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                        Some(pixel.blend_over(RGBAPixel::from_rgba(current)).into_rgba())
+                    });
+                }
+            }
+        }
+        if pixel.alpha() != 0 {
+            self.mark_dirty(px, py, w, h);
+        }
     }
 
     pub fn get_pixel(&self, px: Coord, py: Coord) -> RGBAPixel {
@@ -70,6 +277,14 @@ impl PixelflutImage {
         RGBAPixel::from_rgba(self.pixel_data[i].load(Ordering::Relaxed))
     }
 
+    /// The pixels of row `py`, for consumers that convert the pixels while copying them instead of scanning out
+    pub fn row(&self, py: Coord) -> impl Iterator<Item = RGBAPixel> + '_ {
+        let start = self.index(0, py);
+        self.pixel_data[start..start + self.width as usize]
+            .iter()
+            .map(|pixel| RGBAPixel::from_rgba(pixel.load(Ordering::Relaxed)))
+    }
+
     pub fn scanout_size(&self) -> usize {
         self.pixel_data.len() * size_of::<AtomicU32>()
     }
@@ -86,3 +301,132 @@ impl PixelflutImage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PixelflutImage, RGBAPixel};
+    use std::{sync::Barrier, thread};
+
+    #[test]
+    fn test_blend_over() {
+        let red = RGBAPixel::new_rgb(0xFF, 0, 0);
+        let blue = RGBAPixel::new_rgb(0, 0, 0xFF);
+        assert_eq!(blue.blend_over(red).into_rgba(), blue.into_rgba());
+        assert_eq!(
+            RGBAPixel::new_rgba(0, 0, 0xFF, 0).blend_over(red).into_rgba(),
+            red.into_rgba()
+        );
+        assert_eq!(
+            RGBAPixel::new_rgba(0, 0, 0xFF, 0x80).blend_over(red).into_rgba(),
+            RGBAPixel::new_rgb(0x7F, 0, 0x80).into_rgba()
+        );
+    }
+
+    #[test]
+    fn test_fill_rect() {
+        let image = PixelflutImage::new_with(16, 8);
+        let red = RGBAPixel::new_rgb(0xFF, 0, 0);
+
+        assert_eq!(image.clip_rect(10, 4, 100, 2), Some((10, 4, 6, 2)));
+        assert_eq!(image.clip_rect(16, 0, 1, 1), None);
+        assert_eq!(image.clip_rect(0, 0, 0, 1), None);
+
+        let (x, y, w, h) = image.clip_rect(10, 4, 100, 2).unwrap();
+        image.fill_rect(x, y, w, h, red);
+        for py in 0..image.height {
+            for px in 0..image.width {
+                let inside = px >= 10 && (4..6).contains(&py);
+                let expected = if inside {
+                    red
+                } else {
+                    RGBAPixel::new_rgb(0, 0, 0)
+                };
+                assert_eq!(image.get_pixel(px, py).into_rgba(), expected.into_rgba());
+            }
+        }
+
+        image.fill_rect(0, 0, 16, 8, RGBAPixel::new_rgba(0, 0, 0xFF, 0x80));
+        assert_eq!(image.get_pixel(0, 0).channels(), [0, 0, 0x80, 0xFF]);
+        assert_eq!(image.get_pixel(15, 5).channels(), [0x7F, 0, 0x80, 0xFF]);
+    }
+
+    #[test]
+    fn test_generation() {
+        let image = PixelflutImage::new_with(16, 8);
+        let generation = image.generation();
+        assert_eq!(image.generation(), generation);
+
+        image.blend_pixel(1, 1, RGBAPixel::new_rgba(0xFF, 0, 0, 0));
+        image.fill_rect(0, 0, 4, 4, RGBAPixel::new_rgba(0xFF, 0, 0, 0));
+        assert_eq!(image.generation(), generation);
+
+        image.set_pixel(1, 1, RGBAPixel::new_rgb(0xFF, 0, 0));
+        image.set_pixel(2, 1, RGBAPixel::new_rgb(0xFF, 0, 0));
+        let changed = image.generation();
+        assert_ne!(changed, generation);
+        assert_eq!(image.generation(), changed);
+
+        image.fill_rect(0, 0, 4, 4, RGBAPixel::new_rgba(0xFF, 0, 0, 0x80));
+        assert_ne!(image.generation(), changed);
+    }
+
+    #[test]
+    fn test_dirty_tiles() {
+        let image = PixelflutImage::new_with(80, 40);
+        assert_eq!((image.tiles_x(), image.tiles_y()), (3, 2));
+        assert_eq!(image.tile_rect(2), (64, 0, 16, 32));
+        assert_eq!(image.tile_rect(4), (32, 32, 32, 8));
+
+        assert_eq!(image.take_dirty_tiles().len(), 6);
+        assert!(image.take_dirty_tiles().is_empty());
+
+        let red = RGBAPixel::new_rgb(0xFF, 0, 0);
+        image.set_pixel(33, 1, red);
+        image.blend_pixel(79, 39, RGBAPixel::new_rgba(0xFF, 0, 0, 0x80));
+        assert_eq!(image.take_dirty_tiles().iter().collect::<Vec<_>>(), [1, 5]);
+
+        image.fill_rect(31, 31, 2, 2, red);
+        assert_eq!(
+            image.take_dirty_tiles().iter().collect::<Vec<_>>(),
+            [0, 1, 3, 4]
+        );
+        image.fill_rect(0, 0, 80, 40, RGBAPixel::new_rgba(0xFF, 0, 0, 0));
+        assert!(image.take_dirty_tiles().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_blend() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 12;
+        let image = PixelflutImage::new_with(64, 64);
+        let translucent = RGBAPixel::new_rgba(0xFF, 0xFF, 0xFF, 16);
+
+        // Every blend moves the color further towards white, so a lost update would leave a pixel darker
+        let mut expected = RGBAPixel::new_rgb(0, 0, 0);
+        for _ in 0..THREADS * ROUNDS {
+            expected = translucent.blend_over(expected);
+        }
+
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    barrier.wait();
+                    for _ in 0..ROUNDS {
+                        for y in 0..image.height {
+                            for x in 0..image.width {
+                                image.blend_pixel(x, y, translucent);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        for y in 0..image.height {
+            for x in 0..image.width {
+                assert_eq!(image.get_pixel(x, y).into_rgba(), expected.into_rgba());
+            }
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    game::PixelflutGame,
+    image::{Coord, PixelflutImage, RGBAPixel},
+    shutdown::Shutdown,
+};
+
+#[derive(Debug)]
+pub struct JournalError {
+    pub path: String,
+    pub error: io::Error,
+}
+
+impl Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "journal '{}': {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+/// How often the IO threads' buffers are written out
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+const TAG_SESSION: u8 = 0;
+const TAG_SET_PIXEL: u8 = 1;
+const TAG_RECT: u8 = 2;
+
+/// Tag, timestamp and connection id; all integers are little endian
+const ENTRY_HEADER_LEN: usize = 1 + 8 + 4;
+
+fn payload_len(tag: u8) -> Option<usize> {
+    match tag {
+        TAG_SESSION => Some(2 * 4),
+        TAG_SET_PIXEL => Some(3 * 4),
+        TAG_RECT => Some(5 * 4),
+        _ => None,
+    }
+}
+
+/// A mutation of the canvas, in absolute coordinates
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JournalRecord {
+    /// Written whenever the server starts, since a journal is appended to across runs
+    Session { width: Coord, height: Coord },
+    SetPixel {
+        x: Coord,
+        y: Coord,
+        pixel: RGBAPixel,
+    },
+    Rect {
+        x: Coord,
+        y: Coord,
+        w: Coord,
+        h: Coord,
+        pixel: RGBAPixel,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct JournalEntry {
+    /// Microseconds since the Unix epoch
+    pub time_us: u64,
+    /// 0 for the server itself
+    pub connection: u32,
+    pub record: JournalRecord,
+}
+
+impl JournalEntry {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let (tag, fields): (u8, &[u32]) = match self.record {
+            JournalRecord::Session { width, height } => (TAG_SESSION, &[width, height]),
+            JournalRecord::SetPixel { x, y, pixel } => (TAG_SET_PIXEL, &[x, y, pixel.into_rgba()]),
+            JournalRecord::Rect { x, y, w, h, pixel } => {
+                (TAG_RECT, &[x, y, w, h, pixel.into_rgba()])
+            }
+        };
+        out.push(tag);
+        out.extend_from_slice(&self.time_us.to_le_bytes());
+        out.extend_from_slice(&self.connection.to_le_bytes());
+        for field in fields {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    fn decode(header: &[u8; ENTRY_HEADER_LEN], payload: &[u8]) -> JournalEntry {
+        let field = |i: usize| u32::from_le_bytes(*payload[i * 4..].first_chunk().unwrap());
+        let record = match header[0] {
+            TAG_SESSION => JournalRecord::Session {
+                width: field(0),
+                height: field(1),
+            },
+            TAG_SET_PIXEL => JournalRecord::SetPixel {
+                x: field(0),
+                y: field(1),
+                pixel: RGBAPixel::from_rgba(field(2)),
+            },
+            TAG_RECT => JournalRecord::Rect {
+                x: field(0),
+                y: field(1),
+                w: field(2),
+                h: field(3),
+                pixel: RGBAPixel::from_rgba(field(4)),
+            },
+            tag => unreachable!("unknown tag {tag}"),
+        };
+        JournalEntry {
+            time_us: u64::from_le_bytes(*header[1..].first_chunk().unwrap()),
+            connection: u32::from_le_bytes(*header[9..].first_chunk().unwrap()),
+            record,
+        }
+    }
+
+    /// Apply the record to `image`, clipping it to the canvas
+    pub fn apply(&self, image: &PixelflutImage) {
+        match self.record {
+            JournalRecord::Session { .. } => {}
+            JournalRecord::SetPixel { x, y, pixel } => {
+                if image.bounds_check(x, y) {
+                    image.draw_pixel(x, y, pixel);
+                }
+            }
+            JournalRecord::Rect { x, y, w, h, pixel } => {
+                if let Some((x, y, w, h)) = image.clip_rect(x, y, w, h) {
+                    image.fill_rect(x, y, w, h, pixel);
+                }
+            }
+        }
+    }
+}
+
+/// Timestamps and connection ids for the journal. Entries are buffered by each IO thread and written by
+/// [journal_loop], so recording them is cheap.
+pub struct Journal {
+    enabled: bool,
+    epoch: Instant,
+    epoch_us: u64,
+    next_connection: AtomicU32,
+}
+
+impl Journal {
+    pub fn new(enabled: bool) -> Self {
+        let since_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            enabled,
+            // Measure from a monotonic clock, so timestamps never go backwards
+            epoch: Instant::now(),
+            epoch_us: since_unix.as_micros() as u64,
+            next_connection: AtomicU32::new(1),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn now_us(&self) -> u64 {
+        self.epoch_us + self.epoch.elapsed().as_micros() as u64
+    }
+
+    pub fn next_connection_id(&self) -> u32 {
+        self.next_connection.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Open the journal for appending and start a new session
+pub fn open_journal(path: &str, game: &PixelflutGame) -> Result<BufWriter<File>, JournalError> {
+    let wrap = |error| JournalError {
+        path: path.to_owned(),
+        error,
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(wrap)?;
+
+    // Cut off an entry torn by a crash, since everything appended after it would be misaligned
+    let mut reader = JournalReader::new(path, file.try_clone().map_err(wrap)?);
+    for entry in &mut reader {
+        entry?;
+    }
+    file.set_len(reader.complete_len).map_err(wrap)?;
+    file.seek(SeekFrom::Start(reader.complete_len))
+        .map_err(wrap)?;
+    let mut writer = BufWriter::new(file);
+
+    let mut session = Vec::new();
+    JournalEntry {
+        time_us: game.journal().now_us(),
+        connection: 0,
+        record: JournalRecord::Session {
+            width: game.image().width,
+            height: game.image().height,
+        },
+    }
+    .encode(&mut session);
+    writer.write_all(&session).map_err(wrap)?;
+    writer.flush().map_err(wrap)?;
+    Ok(writer)
+}
+
+/// Write the buffered entries of all threads, ordered by time. The entries of each thread already are.
+fn write_merged(out: &mut impl Write, buffers: &[Vec<u8>]) -> io::Result<()> {
+    let entry_time = |buf: &[u8]| u64::from_le_bytes(*buf[1..].first_chunk().unwrap());
+    let mut rest: Vec<&[u8]> = buffers.iter().map(|buf| buf.as_slice()).collect();
+    while let Some(i) = (0..rest.len())
+        .filter(|&i| !rest[i].is_empty())
+        .min_by_key(|&i| entry_time(rest[i]))
+    {
+        let len = ENTRY_HEADER_LEN + payload_len(rest[i][0]).unwrap();
+        out.write_all(&rest[i][..len])?;
+        rest[i] = &rest[i][len..];
+    }
+    out.flush()
+}
+
+/// Periodically write the entries buffered by the IO threads, until they stopped (see
+/// [PixelflutGame::io_stopped]), so that the commands they still execute on shutdown are written too.
+pub fn journal_loop(mut writer: BufWriter<File>, game: &PixelflutGame) {
+    loop {
+        let stop = game.io_stopped().wait_timeout(FLUSH_INTERVAL);
+        let buffers: Vec<Vec<u8>> = game
+            .workers()
+            .iter()
+            .map(|worker| mem::take(&mut *worker.journal_buffer.lock().unwrap()))
+            .collect();
+        if let Err(e) = write_merged(&mut writer, &buffers) {
+            eprintln!("error: journal: {e}");
+        }
+        if stop {
+            break;
+        }
+    }
+    if let Err(e) = writer.get_ref().sync_all() {
+        eprintln!("error: journal: {e}");
+    }
+}
+
+/// Reads the entries of a journal. A truncated entry at the end (e.g. after a crash) is ignored.
+pub struct JournalReader {
+    path: String,
+    reader: BufReader<File>,
+    /// Bytes of the complete entries read so far
+    complete_len: u64,
+}
+
+impl JournalReader {
+    pub fn open(path: &str) -> Result<JournalReader, JournalError> {
+        let file = File::open(path).map_err(|error| JournalError {
+            path: path.to_owned(),
+            error,
+        })?;
+        Ok(JournalReader::new(path, file))
+    }
+
+    fn new(path: &str, file: File) -> JournalReader {
+        JournalReader {
+            path: path.to_owned(),
+            reader: BufReader::new(file),
+            complete_len: 0,
+        }
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<JournalEntry>> {
+        let mut header = [0u8; ENTRY_HEADER_LEN];
+        let mut payload = [0u8; 5 * 4];
+        let read = |reader: &mut BufReader<File>, buf: &mut [u8]| match reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        };
+        if !read(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let Some(len) = payload_len(header[0]) else {
+            let msg = format!("unknown entry type {}", header[0]);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        };
+        if !read(&mut self.reader, &mut payload[..len])? {
+            return Ok(None);
+        }
+        self.complete_len += (ENTRY_HEADER_LEN + len) as u64;
+        Ok(Some(JournalEntry::decode(&header, &payload[..len])))
+    }
+
+    /// The canvas dimensions of the first session
+    pub fn dimensions(path: &str) -> Result<(Coord, Coord), JournalError> {
+        match JournalReader::open(path)?.next().transpose()? {
+            Some(JournalEntry {
+                record: JournalRecord::Session { width, height },
+                ..
+            }) => Ok((width, height)),
+            _ => Err(JournalError {
+                path: path.to_owned(),
+                error: io::Error::new(io::ErrorKind::InvalidData, "does not start with a session"),
+            }),
+        }
+    }
+}
+
+impl Iterator for JournalReader {
+    type Item = Result<JournalEntry, JournalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry()
+            .map_err(|error| JournalError {
+                path: self.path.clone(),
+                error,
+            })
+            .transpose()
+    }
+}
+
+/// Reconstruct the canvas as it was `at` after the start of the journal, or at its end
+pub fn reconstruct(path: &str, at: Option<Duration>) -> Result<PixelflutImage, JournalError> {
+    let (width, height) = JournalReader::dimensions(path)?;
+    let image = PixelflutImage::new_with(width, height);
+    let mut cutoff = None;
+    for entry in JournalReader::open(path)? {
+        let entry = entry?;
+        let cutoff = *cutoff
+            .get_or_insert_with(|| at.map_or(u64::MAX, |at| entry.time_us + at.as_micros() as u64));
+        // Entries of different threads can be slightly out of order, so don't stop at the first later one
+        if entry.time_us <= cutoff {
+            entry.apply(&image);
+        }
+    }
+    Ok(image)
+}
+
+/// Apply the journal to `image` in real time, sped up by `speed`. Gaps between sessions are skipped.
+pub fn replay_loop(
+    path: &str,
+    image: &PixelflutImage,
+    speed: f64,
+    shutdown: &Shutdown,
+) -> Result<(), JournalError> {
+    let mut base = None;
+    for entry in JournalReader::open(path)? {
+        let entry = entry?;
+        if let JournalRecord::Session { .. } = entry.record {
+            base = None;
+            continue;
+        }
+        let (start_us, start) = *base.get_or_insert((entry.time_us, Instant::now()));
+        let due =
+            start + Duration::from_micros(entry.time_us.saturating_sub(start_us)).div_f64(speed);
+        let now = Instant::now();
+        if due > now && shutdown.wait_timeout(due - now) {
+            break;
+        }
+        entry.apply(image);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        open_journal, reconstruct, replay_loop, write_merged, JournalEntry, JournalReader,
+        JournalRecord,
+    };
+    use crate::core::{
+        config::Config,
+        game::PixelflutGame,
+        image::{PixelflutImage, RGBAPixel},
+        shutdown::Shutdown,
+    };
+    use std::{fs, time::Duration};
+
+    fn entry(time_us: u64, record: JournalRecord) -> JournalEntry {
+        JournalEntry {
+            time_us,
+            connection: 7,
+            record,
+        }
+    }
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let path = std::env::temp_dir().join(format!("pixelflut-journal-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let red = RGBAPixel::new_rgb(0xFF, 0, 0);
+        let green = RGBAPixel::new_rgba(0, 0xFF, 0, 0x80);
+        let session = entry(
+            0,
+            JournalRecord::Session {
+                width: 4,
+                height: 2,
+            },
+        );
+        let set = |t, x, pixel| entry(t, JournalRecord::SetPixel { x, y: 0, pixel });
+        let rect = entry(
+            3_000_000,
+            JournalRecord::Rect {
+                x: 1,
+                y: 0,
+                w: 10,
+                h: 10,
+                pixel: green,
+            },
+        );
+
+        // Two threads, whose entries interleave in time
+        let mut thread_a = Vec::new();
+        let mut thread_b = Vec::new();
+        session.encode(&mut thread_a);
+        set(1_000_000, 0, red).encode(&mut thread_a);
+        rect.encode(&mut thread_a);
+        set(2_000_000, 1, red).encode(&mut thread_b);
+        let mut journal = Vec::new();
+        write_merged(&mut journal, &[thread_a, thread_b]).unwrap();
+        // A crash left half an entry at the end
+        journal.extend_from_slice(&[1, 2, 3]);
+        fs::write(path, &journal).unwrap();
+
+        let entries: Vec<JournalEntry> = JournalReader::open(path)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                session,
+                set(1_000_000, 0, red),
+                set(2_000_000, 1, red),
+                rect
+            ]
+        );
+
+        let at = |secs| reconstruct(path, Some(Duration::from_secs(secs))).unwrap();
+        let channels = |image: &PixelflutImage, x| image.get_pixel(x, 0).channels();
+        assert_eq!(channels(&at(0), 0), [0, 0, 0, 0xFF]);
+        assert_eq!(channels(&at(1), 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(channels(&at(2), 1), [0xFF, 0, 0, 0xFF]);
+        let end = reconstruct(path, None).unwrap();
+        assert_eq!(channels(&end, 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(channels(&end, 1), [0x7F, 0x80, 0, 0xFF]);
+        assert_eq!(channels(&end, 3), [0, 0x80, 0, 0xFF]);
+
+        // 3 seconds of journal at 1000x speed
+        let replayed = PixelflutImage::new_with(4, 2);
+        replay_loop(path, &replayed, 1000.0, &Shutdown::new()).unwrap();
+        for x in 0..4 {
+            assert_eq!(channels(&replayed, x), channels(&end, x));
+        }
+
+        // The next run appends its session in place of the torn entry
+        let game = PixelflutGame::new(&Config {
+            image_width: 4,
+            image_height: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        drop(open_journal(path, game).unwrap());
+        let entries: Vec<JournalEntry> = JournalReader::open(path)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(entries.len(), 5);
+        assert!(matches!(
+            entries[4].record,
+            JournalRecord::Session {
+                width: 4,
+                height: 2
+            }
+        ));
+
+        fs::remove_file(path).unwrap();
+    }
+}
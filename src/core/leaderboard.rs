@@ -0,0 +1,200 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    mem,
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use super::{config::LeaderboardConfig, game::PixelflutGame, state::PixelflutThreadState};
+
+/// How many addresses the leaderboard shows
+pub const LEADERBOARD_SIZE: usize = 10;
+
+/// How often the tallies of the IO threads are merged into the leaderboard
+const MERGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// At most this many addresses are tracked. Beyond that, only the larger half is kept, so a new address has a few
+/// merges to climb before it can be evicted again.
+const MAX_TRACKED: usize = 4096;
+
+/// Pixels set per source address, across all connections and IO threads
+pub struct Leaderboard {
+    totals: Mutex<HashMap<IpAddr, u64>>,
+    /// The top entries as of the last merge, so that reading them doesn't need to look at all addresses
+    top: Mutex<Vec<(IpAddr, u64)>>,
+    command: bool,
+    mask_addresses: bool,
+}
+
+impl Leaderboard {
+    pub fn new(config: &LeaderboardConfig) -> Self {
+        Self {
+            totals: Mutex::new(HashMap::new()),
+            top: Mutex::new(Vec::new()),
+            command: config.command,
+            mask_addresses: config.mask_addresses,
+        }
+    }
+
+    /// Move the tallies of the IO threads into the totals
+    pub fn merge(&self, workers: &[PixelflutThreadState]) {
+        let mut totals = self.totals.lock().unwrap();
+        for worker in workers {
+            let tally = mem::take(&mut *worker.pixels_by_ip.lock().unwrap());
+            for (ip, pixels) in tally {
+                *totals.entry(ip).or_default() += pixels;
+            }
+        }
+
+        if totals.len() > MAX_TRACKED {
+            let keep = MAX_TRACKED / 2;
+            let mut entries: Vec<(IpAddr, u64)> = totals.drain().collect();
+            entries.select_nth_unstable_by_key(keep - 1, |&(ip, pixels)| (Reverse(pixels), ip));
+            entries.truncate(keep);
+            totals.extend(entries);
+        }
+
+        // Min-heap of the best entries so far, so the worst of them is the one to replace
+        let mut top = BinaryHeap::with_capacity(LEADERBOARD_SIZE + 1);
+        for (&ip, &pixels) in totals.iter() {
+            top.push(Reverse((pixels, Reverse(ip))));
+            if top.len() > LEADERBOARD_SIZE {
+                top.pop();
+            }
+        }
+        *self.top.lock().unwrap() = top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((pixels, Reverse(ip)))| (ip, pixels))
+            .collect();
+    }
+
+    /// The addresses that set the most pixels, most first, as of the last merge
+    pub fn top(&self) -> Vec<(IpAddr, u64)> {
+        self.top.lock().unwrap().clone()
+    }
+
+    /// [Leaderboard::top] as shown to clients, with the addresses masked if configured
+    pub fn top_shown(&self) -> Vec<(String, u64)> {
+        self.top()
+            .into_iter()
+            .map(|(ip, pixels)| {
+                let shown = if self.mask_addresses {
+                    mask(ip)
+                } else {
+                    ip.to_string()
+                };
+                (shown, pixels)
+            })
+            .collect()
+    }
+
+    /// Whether clients may ask for the leaderboard with the LEADERBOARD command
+    pub fn command_enabled(&self) -> bool {
+        self.command
+    }
+}
+
+/// Only the /24 of an IPv4 address or the /64 of an IPv6 address
+fn mask(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{a}.{b}.{c}.*")
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.segments();
+            format!("{a:x}:{b:x}:{c:x}:{d:x}:*")
+        }
+    }
+}
+
+/// Periodically merge the leaderboard until shutdown, and once more after it. Also evicts idle addresses from the
+/// client limits, which needs the same kind of periodic sweep.
+pub fn leaderboard_loop(game: &PixelflutGame) {
+    loop {
+        let stop = game.shutdown().wait_timeout(MERGE_INTERVAL);
+        game.leaderboard().merge(game.workers());
+        game.limits().evict_idle();
+        if stop {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mask, LEADERBOARD_SIZE, MAX_TRACKED};
+    use crate::core::{config::Config, game::PixelflutGame};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_merge() {
+        let game = PixelflutGame::new(&Config {
+            num_io_threads: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        let ip = |last| IpAddr::V4(Ipv4Addr::new(10, 0, 0, last));
+        game.for_worker(0).count_pixels_by_ip(ip(1), 5);
+        game.for_worker(1).count_pixels_by_ip(ip(1), 10);
+        game.for_worker(1).count_pixels_by_ip(ip(2), 20);
+        assert!(game.leaderboard().top().is_empty());
+
+        game.leaderboard().merge(game.workers());
+        assert_eq!(game.leaderboard().top(), [(ip(2), 20), (ip(1), 15)]);
+
+        // Merging again must not count anything twice
+        game.for_worker(0).count_pixels_by_ip(ip(1), 10);
+        game.leaderboard().merge(game.workers());
+        assert_eq!(game.leaderboard().top(), [(ip(1), 25), (ip(2), 20)]);
+
+        for last in 3..30 {
+            game.for_worker(0).count_pixels_by_ip(ip(last), last as u64);
+        }
+        game.leaderboard().merge(game.workers());
+        let top = game.leaderboard().top();
+        assert_eq!(top.len(), LEADERBOARD_SIZE);
+        assert_eq!(top[0], (ip(29), 29));
+        assert_eq!(top[LEADERBOARD_SIZE - 1], (ip(21), 21));
+    }
+
+    #[test]
+    fn test_merge_bounded() {
+        let game = PixelflutGame::new(&Config::default()).unwrap();
+        let ip = |i: u32| IpAddr::V4(Ipv4Addr::from(0x0A00_0000 + i));
+        for i in 1..=MAX_TRACKED as u32 + 1 {
+            game.for_worker(0).count_pixels_by_ip(ip(i), i as u64);
+        }
+        game.leaderboard().merge(game.workers());
+        let totals = game.leaderboard().totals.lock().unwrap().clone();
+        assert_eq!(totals.len(), MAX_TRACKED / 2);
+        assert!(totals
+            .values()
+            .all(|&pixels| pixels > MAX_TRACKED as u64 / 2 + 1));
+        let top = game.leaderboard().top();
+        assert_eq!(top[0], (ip(MAX_TRACKED as u32 + 1), MAX_TRACKED as u64 + 1));
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(mask(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 17))), "192.0.2.*");
+        let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6));
+        assert_eq!(mask(ip), "2001:db8:1:2:*");
+        let mapped = IpAddr::V6(Ipv4Addr::new(192, 0, 2, 17).to_ipv6_mapped());
+        assert_eq!(mask(mapped), "192.0.2.*");
+
+        let mut config = Config::default();
+        config.leaderboard.mask_addresses = true;
+        let game = PixelflutGame::new(&config).unwrap();
+        game.for_worker(0)
+            .count_pixels_by_ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 17)), 3);
+        game.leaderboard().merge(game.workers());
+        assert_eq!(
+            game.leaderboard().top_shown(),
+            [("192.0.2.*".to_owned(), 3)]
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::config::{LimitsConfig, OverLimit};
+
+/// How many pixels a connection takes from the shared bucket at once, so that we don't lock for every pixel
+const CREDIT_BATCH: u64 = 64;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Per-address state, shared by all connections from that address (across all IO threads)
+pub struct IpQuota {
+    connections: usize,
+    bucket: TokenBucket,
+}
+
+/// Connection and pixel rate limits, keyed by source address
+pub struct ClientLimits {
+    config: LimitsConfig,
+    clients: Mutex<HashMap<IpAddr, Arc<Mutex<IpQuota>>>>,
+}
+
+impl ClientLimits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn over_limit(&self) -> OverLimit {
+        self.config.over_limit
+    }
+
+    fn burst(&self) -> f64 {
+        self.config
+            .pixel_burst
+            .or(self.config.pixels_per_second)
+            .unwrap_or(0) as f64
+    }
+
+    /// Whether `quota` has no connections left and a full bucket, so that forgetting it changes nothing
+    fn is_idle(&self, quota: &IpQuota) -> bool {
+        let rate = self.config.pixels_per_second.unwrap_or(0) as f64;
+        let refilled =
+            quota.bucket.tokens + quota.bucket.last_refill.elapsed().as_secs_f64() * rate;
+        quota.connections == 0 && (rate == 0.0 || refilled >= self.burst())
+    }
+
+    /// Forget idle addresses whose last connection ended before their bucket refilled. Call this periodically, since
+    /// such addresses are otherwise only removed when they connect again.
+    pub fn evict_idle(&self) {
+        let mut clients = self.clients.lock().unwrap();
+        // Guards of connectionless sources still hold their entry, so it must not be replaced yet
+        clients.retain(|_, quota| {
+            Arc::strong_count(quota) > 1 || !self.is_idle(&quota.lock().unwrap())
+        });
+    }
+
+    fn guard(&'static self, ip: IpAddr, connection: bool) -> Option<ConnectionGuard> {
+        let mut clients = self.clients.lock().unwrap();
+        let quota = clients.entry(ip).or_insert_with(|| {
+            Arc::new(Mutex::new(IpQuota {
+                connections: 0,
+                bucket: TokenBucket {
+                    tokens: self.burst(),
+                    last_refill: Instant::now(),
+                },
+            }))
+        });
+
+        if connection {
+            let mut quota = quota.lock().unwrap();
+            if let Some(max) = self.config.max_connections_per_ip
+                && quota.connections >= max
+            {
+                return None;
+            }
+            quota.connections += 1;
+        }
+
+        Some(ConnectionGuard {
+            limits: self,
+            ip,
+            quota: quota.clone(),
+            connection,
+            credit: 0,
+        })
+    }
+
+    /// Register a new connection from `ip`. Returns None if it exceeds `max_connections_per_ip`.
+    pub fn connect(&'static self, ip: IpAddr) -> Option<ConnectionGuard> {
+        self.guard(ip, true)
+    }
+
+    /// Take pixels from the budget of `ip` without holding a connection, for connectionless (UDP) clients
+    pub fn source(&'static self, ip: IpAddr) -> ConnectionGuard {
+        self.guard(ip, false).unwrap()
+    }
+}
+
+/// Result of [ConnectionGuard::take_pixels]
+pub enum PixelGrant {
+    Granted,
+    /// Not enough budget; more will be available after the given duration
+    Exhausted(Duration),
+}
+
+/// Holds one of the connections of an address (unless created by [ClientLimits::source]), and a bit of pixel credit
+/// taken from its bucket. The connection is released on drop.
+pub struct ConnectionGuard {
+    limits: &'static ClientLimits,
+    ip: IpAddr,
+    quota: Arc<Mutex<IpQuota>>,
+    connection: bool,
+    credit: u64,
+}
+
+impl ConnectionGuard {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn take_pixels(&mut self, n: u64) -> PixelGrant {
+        let Some(rate) = self.limits.config.pixels_per_second else {
+            return PixelGrant::Granted;
+        };
+        if self.credit >= n {
+            self.credit -= n;
+            return PixelGrant::Granted;
+        }
+
+        let rate = rate as f64;
+        let burst = self.limits.burst();
+        let mut quota = self.quota.lock().unwrap();
+        let bucket = &mut quota.bucket;
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+
+        // Partial grants accumulate in credit, so requests larger than the burst still succeed eventually
+        let want = (n - self.credit).max(CREDIT_BATCH) as f64;
+        let granted = want.min(bucket.tokens).floor();
+        bucket.tokens -= granted;
+        self.credit += granted as u64;
+
+        if self.credit >= n {
+            self.credit -= n;
+            PixelGrant::Granted
+        } else {
+            let missing = (n - self.credit) as f64;
+            let wait = Duration::from_secs_f64(missing.min(burst.max(1.0)) / rate);
+            PixelGrant::Exhausted(wait.max(Duration::from_millis(1)))
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut clients = self.limits.clients.lock().unwrap();
+        let mut quota = self.quota.lock().unwrap();
+        if self.connection {
+            quota.connections -= 1;
+        }
+        quota.bucket.tokens += self.credit as f64;
+
+        // Forget idle addresses, but only once their bucket has refilled; otherwise reconnecting would reset the
+        // rate limit. The rest are left to [ClientLimits::evict_idle]. Other guards (of connectionless sources)
+        // may still share the entry, or it may already be a newer one, and then it must stay.
+        if self.limits.is_idle(&quota)
+            && let Some(entry) = clients.get(&self.ip)
+            && Arc::ptr_eq(entry, &self.quota)
+            && Arc::strong_count(&self.quota) == 2
+        {
+            clients.remove(&self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientLimits, PixelGrant};
+    use crate::core::config::LimitsConfig;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    fn limits(config: LimitsConfig) -> &'static ClientLimits {
+        Box::leak(Box::new(ClientLimits::new(&config)))
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        // A source that outlives a connection of its address must not forget the connections after that one
+        let single = limits(LimitsConfig {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+        let s = single.source(a);
+        drop(single.connect(a).unwrap());
+        let _c = single.connect(a).unwrap();
+        drop(s);
+        assert!(single.connect(a).is_none());
+
+        let limits = limits(LimitsConfig {
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+
+        let c1 = limits.connect(a).unwrap();
+        let _c2 = limits.connect(a).unwrap();
+        assert!(limits.connect(a).is_none());
+        let _c3 = limits.connect(b).unwrap();
+
+        drop(c1);
+        let _c4 = limits.connect(a).unwrap();
+
+        // Connectionless sources are not limited, and do not take up a connection
+        let s = limits.source(a);
+        drop(s);
+        assert!(limits.connect(a).is_none());
+    }
+
+    #[test]
+    fn test_pixel_budget_shared() {
+        let limits = limits(LimitsConfig {
+            pixels_per_second: Some(1),
+            pixel_burst: Some(100),
+            ..Default::default()
+        });
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut c1 = limits.connect(ip).unwrap();
+        let mut c2 = limits.connect(ip).unwrap();
+
+        let mut granted = 0;
+        for _ in 0..100 {
+            for c in [&mut c1, &mut c2] {
+                if let PixelGrant::Granted = c.take_pixels(1) {
+                    granted += 1;
+                }
+            }
+        }
+        // Both connections draw from the same bucket (plus at most a token refilled during the test)
+        assert!((100..=101).contains(&granted), "granted {granted}");
+
+        // Dropping the connections must not reset the bucket
+        drop((c1, c2));
+        let mut c3 = limits.connect(ip).unwrap();
+        assert!(matches!(c3.take_pixels(50), PixelGrant::Exhausted(_)));
+        let mut source = limits.source(ip);
+        assert!(matches!(source.take_pixels(50), PixelGrant::Exhausted(_)));
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let limits = limits(LimitsConfig {
+            pixels_per_second: Some(1000),
+            pixel_burst: Some(200),
+            ..Default::default()
+        });
+        let ip = |last| IpAddr::V4(Ipv4Addr::new(10, 0, 0, last));
+        let len = || limits.clients.lock().unwrap().len();
+
+        let mut c1 = limits.connect(ip(1)).unwrap();
+        let mut c2 = limits.connect(ip(2)).unwrap();
+        let _c3 = limits.connect(ip(3)).unwrap();
+        for c in [&mut c1, &mut c2] {
+            assert!(matches!(c.take_pixels(200), PixelGrant::Granted));
+        }
+        // Both buckets are empty, so the addresses are kept when their connections end
+        drop((c1, c2));
+        assert_eq!(len(), 3);
+        limits.evict_idle();
+        assert_eq!(len(), 3);
+
+        // Once refilled they are evicted, unless a connection or source still holds them
+        let _s2 = limits.source(ip(2));
+        std::thread::sleep(Duration::from_millis(300));
+        limits.evict_idle();
+        let mut left: Vec<IpAddr> = limits.clients.lock().unwrap().keys().copied().collect();
+        left.sort();
+        assert_eq!(left, [ip(2), ip(3)]);
+    }
+}
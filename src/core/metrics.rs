@@ -0,0 +1,188 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::game::PixelflutGame;
+
+#[derive(Clone, Copy)]
+pub enum CommandKind {
+    Help,
+    Size,
+    SetPixel,
+    GetPixel,
+    Rect,
+    Offset,
+    Leaderboard,
+}
+
+impl CommandKind {
+    const ALL: [CommandKind; 7] = [
+        CommandKind::Help,
+        CommandKind::Size,
+        CommandKind::SetPixel,
+        CommandKind::GetPixel,
+        CommandKind::Rect,
+        CommandKind::Offset,
+        CommandKind::Leaderboard,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandKind::Help => "help",
+            CommandKind::Size => "size",
+            CommandKind::SetPixel => "set_pixel",
+            CommandKind::GetPixel => "get_pixel",
+            CommandKind::Rect => "rect",
+            CommandKind::Offset => "offset",
+            CommandKind::Leaderboard => "leaderboard",
+        }
+    }
+}
+
+/// Counters of a single IO thread. Only that thread writes to them, so they are never contended.
+#[derive(Default)]
+#[repr(align(64))] // Keep the slots of different threads on different cache lines
+pub struct WorkerMetrics {
+    pub pixels_set: AtomicU64,
+    commands: [AtomicU64; CommandKind::ALL.len()],
+    pub parse_errors: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub connections_accepted: AtomicU64,
+    pub connections_closed: AtomicU64,
+    /// Closed connections that were handed to this thread by the accepting one. Unlike `connections_closed`, this
+    /// leaves out the clients that the thread serves on its own, so it can be balanced against the hand-offs.
+    pub handoffs_closed: AtomicU64,
+}
+
+pub fn count(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl WorkerMetrics {
+    pub fn count_command(&self, kind: CommandKind) {
+        count(&self.commands[kind as usize], 1);
+    }
+}
+
+/// Counters that are not owned by an IO thread
+#[derive(Default)]
+pub struct GlobalMetrics {
+    pub frames_pushed: AtomicU64,
+}
+
+type CounterFn = fn(&WorkerMetrics) -> &AtomicU64;
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render_metrics(game: &PixelflutGame) -> String {
+    let mut out = String::new();
+    let workers = || game.workers().iter().map(|w| &w.metrics).enumerate();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    let per_worker: [(&str, &str, CounterFn); 4] = [
+        ("pixelflut_pixels_set_total", "Pixels set by clients", |m| {
+            &m.pixels_set
+        }),
+        (
+            "pixelflut_parse_errors_total",
+            "Invalid or overlong command lines",
+            |m| &m.parse_errors,
+        ),
+        (
+            "pixelflut_bytes_received_total",
+            "Bytes received from clients",
+            |m| &m.bytes_received,
+        ),
+        (
+            "pixelflut_connections_accepted_total",
+            "Connections handed to the IO worker",
+            |m| &m.connections_accepted,
+        ),
+    ];
+    for (name, help, counter) in per_worker {
+        metric_header(&mut out, name, "counter", help);
+        for (id, m) in workers() {
+            writeln!(out, "{name}{{worker=\"{id}\"}} {}", load(counter(m))).unwrap();
+        }
+    }
+
+    let name = "pixelflut_connections_open";
+    metric_header(&mut out, name, "gauge", "Currently open connections");
+    for (id, m) in workers() {
+        let open = load(&m.connections_accepted).saturating_sub(load(&m.connections_closed));
+        writeln!(out, "{name}{{worker=\"{id}\"}} {open}").unwrap();
+    }
+
+    let name = "pixelflut_commands_total";
+    metric_header(&mut out, name, "counter", "Executed commands by type");
+    for (id, m) in workers() {
+        for kind in CommandKind::ALL {
+            let count = load(&m.commands[kind as usize]);
+            let command = kind.name();
+            writeln!(
+                out,
+                "{name}{{worker=\"{id}\",command=\"{command}\"}} {count}"
+            )
+            .unwrap();
+        }
+    }
+
+    let name = "pixelflut_frames_pushed_total";
+    metric_header(
+        &mut out,
+        name,
+        "counter",
+        "Frames pushed into the video pipeline",
+    );
+    writeln!(out, "{name} {}", load(&game.metrics().frames_pushed)).unwrap();
+
+    let name = "pixelflut_leaderboard_pixels";
+    metric_header(
+        &mut out,
+        name,
+        "gauge",
+        "Pixels set by the addresses on the leaderboard",
+    );
+    for (ip, pixels) in game.leaderboard().top() {
+        writeln!(out, "{name}{{ip=\"{ip}\"}} {pixels}").unwrap();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count, render_metrics, CommandKind};
+    use crate::core::{config::Config, game::PixelflutGame};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_render_metrics() {
+        let game = PixelflutGame::new(&Config {
+            num_io_threads: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        let worker = &game.for_worker(1).metrics;
+        count(&worker.pixels_set, 42);
+        count(&worker.connections_accepted, 3);
+        count(&worker.connections_closed, 1);
+        worker.count_command(CommandKind::Rect);
+        game.for_worker(0)
+            .count_pixels_by_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), 7);
+        game.leaderboard().merge(game.workers());
+
+        let text = render_metrics(game);
+        assert!(text.contains("pixelflut_pixels_set_total{worker=\"0\"} 0\n"));
+        assert!(text.contains("pixelflut_pixels_set_total{worker=\"1\"} 42\n"));
+        assert!(text.contains("pixelflut_connections_open{worker=\"1\"} 2\n"));
+        assert!(text.contains("pixelflut_commands_total{worker=\"1\",command=\"rect\"} 1\n"));
+        assert!(text.contains("# TYPE pixelflut_connections_open gauge\n"));
+        assert!(text.contains("pixelflut_leaderboard_pixels{ip=\"127.0.0.1\"} 7\n"));
+    }
+}
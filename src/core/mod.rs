@@ -1,4 +1,13 @@
 pub mod image;
 pub mod state;
 pub mod game;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod limits;
+pub mod snapshot;
+pub mod shutdown;
+pub mod metrics;
+pub mod placement;
+pub mod viewer;
+pub mod journal;
+pub mod overlay;
+pub mod leaderboard;
\ No newline at end of file
@@ -0,0 +1,128 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use super::{config::Config, game::PixelflutGame};
+
+/// How many of the top addresses {leaderboard} shows
+const OVERLAY_LEADERS: usize = 3;
+
+/// How often frontends should refresh the overlay text, which also averages pixels/sec over this period
+pub const OVERLAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Abbreviate large numbers for display, e.g. 1234567 as "1.2M"
+fn human(n: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1_000_000_000, "G"), (1_000_000, "M"), (1_000, "k")];
+    for (scale, unit) in UNITS {
+        if n >= scale {
+            return format!("{:.1}{unit}", n as f64 / scale as f64);
+        }
+    }
+    n.to_string()
+}
+
+/// Formats the statistics for the overlay with the configured text
+pub struct Overlay {
+    /// The configured text, with the placeholders that never change already filled in
+    template: String,
+    previous: Option<(Instant, u64)>,
+}
+
+impl Overlay {
+    pub fn new(config: &Config) -> Self {
+        let template = config
+            .overlay
+            .text
+            .replace("{listen_addr}", &config.listen_addr)
+            .replace("{width}", &config.image_width.to_string())
+            .replace("{height}", &config.image_height.to_string());
+        Self {
+            template,
+            previous: None,
+        }
+    }
+
+    /// The overlay text for the current statistics. Pixels/sec are averaged since the previous call.
+    pub fn text(&mut self, game: &PixelflutGame) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let metrics = || game.workers().iter().map(|w| &w.metrics);
+        let pixels: u64 = metrics().map(|m| load(&m.pixels_set)).sum();
+        let clients: u64 = metrics()
+            .map(|m| load(&m.connections_accepted).saturating_sub(load(&m.connections_closed)))
+            .sum();
+
+        let now = Instant::now();
+        let pixels_per_second = match self.previous {
+            Some((then, previous)) if now > then => {
+                (pixels.saturating_sub(previous) as f64 / (now - then).as_secs_f64()) as u64
+            }
+            _ => 0,
+        };
+        self.previous = Some((now, pixels));
+
+        let leaderboard: Vec<String> = game
+            .leaderboard()
+            .top_shown()
+            .iter()
+            .take(OVERLAY_LEADERS)
+            .enumerate()
+            .map(|(i, (ip, pixels))| format!("{}. {ip} ({})", i + 1, human(*pixels)))
+            .collect();
+
+        self.template
+            .replace("{clients}", &clients.to_string())
+            .replace("{pixels_per_second}", &human(pixels_per_second))
+            .replace("{pixels}", &human(pixels))
+            .replace("{leaderboard}", &leaderboard.join("  "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{human, Overlay};
+    use crate::core::{config::Config, game::PixelflutGame, metrics::count};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_overlay_text() {
+        assert_eq!(human(999), "999");
+        assert_eq!(human(1_250), "1.2k");
+        assert_eq!(human(3_400_000_000), "3.4G");
+
+        let mut config = Config {
+            num_io_threads: 2,
+            image_width: 64,
+            image_height: 32,
+            ..Default::default()
+        };
+        config.overlay.text =
+            "{listen_addr} {width}x{height}: {clients} / {pixels} / {pixels_per_second}".to_owned();
+        let game = PixelflutGame::new(&config).unwrap();
+        let mut overlay = Overlay::new(&config);
+        assert_eq!(overlay.text(game), "127.0.0.1:4000 64x32: 0 / 0 / 0");
+
+        let worker = &game.for_worker(1).metrics;
+        count(&worker.connections_accepted, 3);
+        count(&worker.connections_closed, 1);
+        count(&worker.pixels_set, 2_000_000);
+        let text = overlay.text(game);
+        assert!(
+            text.starts_with("127.0.0.1:4000 64x32: 2 / 2.0M / "),
+            "{text}"
+        );
+        assert!(!text.ends_with("/ 0"), "{text}");
+
+        config.overlay.text = "top: {leaderboard}".to_owned();
+        let mut overlay = Overlay::new(&config);
+        for (last, pixels) in [(1, 1_500), (2, 10), (3, 20), (4, 5)] {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, last));
+            game.for_worker(0).count_pixels_by_ip(ip, pixels);
+        }
+        game.leaderboard().merge(game.workers());
+        assert_eq!(
+            overlay.text(game),
+            "top: 1. 10.0.0.1 (1.5k)  2. 10.0.0.3 (20)  3. 10.0.0.2 (10)"
+        );
+    }
+}
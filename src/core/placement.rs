@@ -0,0 +1,218 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use super::{config::PlacementStrategy, state::PixelflutThreadState};
+
+/// Decides which IO thread a newly accepted connection is handed to
+pub trait Placement {
+    fn choose(&mut self, workers: &[PixelflutThreadState]) -> usize;
+}
+
+pub fn new_placement(strategy: PlacementStrategy, num_workers: usize) -> Box<dyn Placement + Send> {
+    match strategy {
+        PlacementStrategy::Random => Box::new(RandomPlacement),
+        PlacementStrategy::RoundRobin => Box::new(RoundRobinPlacement { next: 0 }),
+        PlacementStrategy::LeastConnections => {
+            Box::new(LeastConnectionsPlacement::new(num_workers))
+        }
+        PlacementStrategy::LeastBytes => Box::new(LeastBytesPlacement::new(
+            num_workers,
+            LeastBytesPlacement::SAMPLE_INTERVAL,
+        )),
+    }
+}
+
+/// Index of the first smallest value
+fn argmin<T: PartialOrd>(values: impl Iterator<Item = T>) -> usize {
+    let mut best: Option<(usize, T)> = None;
+    for (i, value) in values.enumerate() {
+        if best.as_ref().is_none_or(|(_, best)| value < *best) {
+            best = Some((i, value));
+        }
+    }
+    best.map_or(0, |(i, _)| i)
+}
+
+pub struct RandomPlacement;
+
+impl Placement for RandomPlacement {
+    fn choose(&mut self, workers: &[PixelflutThreadState]) -> usize {
+        rand::random_range(0..workers.len())
+    }
+}
+
+pub struct RoundRobinPlacement {
+    next: usize,
+}
+
+impl Placement for RoundRobinPlacement {
+    fn choose(&mut self, workers: &[PixelflutThreadState]) -> usize {
+        let i = self.next % workers.len();
+        self.next = i + 1;
+        i
+    }
+}
+
+/// Picks the thread with the fewest open connections
+pub struct LeastConnectionsPlacement {
+    // We count hand-offs ourselves instead of using connections_accepted: that one lags behind while the
+    // connection is still in the channel, which would put a whole burst of connections onto the same thread.
+    handed_off: Vec<u64>,
+}
+
+impl LeastConnectionsPlacement {
+    pub fn new(num_workers: usize) -> Self {
+        Self {
+            handed_off: vec![0; num_workers],
+        }
+    }
+
+    fn open_connections<'a>(
+        &'a self,
+        workers: &'a [PixelflutThreadState],
+    ) -> impl Iterator<Item = u64> + 'a {
+        workers
+            .iter()
+            .zip(&self.handed_off)
+            .map(|(w, &handed_off)| {
+                handed_off.saturating_sub(w.metrics.handoffs_closed.load(Ordering::Relaxed))
+            })
+    }
+}
+
+impl Placement for LeastConnectionsPlacement {
+    fn choose(&mut self, workers: &[PixelflutThreadState]) -> usize {
+        let i = argmin(self.open_connections(workers));
+        self.handed_off[i] += 1;
+        i
+    }
+}
+
+/// Picks the thread that recently received the fewest bytes per second, breaking ties by open connections
+pub struct LeastBytesPlacement {
+    interval: Duration,
+    last_sample: Instant,
+    last_bytes: Vec<u64>,
+    rates: Vec<f64>,
+    connections: LeastConnectionsPlacement,
+}
+
+impl LeastBytesPlacement {
+    pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Rates are re-sampled from the workers' byte counters at most once per `interval`
+    pub fn new(num_workers: usize, interval: Duration) -> Self {
+        Self {
+            interval,
+            last_sample: Instant::now(),
+            last_bytes: vec![0; num_workers],
+            rates: vec![0.0; num_workers],
+            connections: LeastConnectionsPlacement::new(num_workers),
+        }
+    }
+
+    fn sample(&mut self, workers: &[PixelflutThreadState]) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_sample);
+        if elapsed < self.interval {
+            return;
+        }
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        for (i, worker) in workers.iter().enumerate() {
+            let bytes = worker.metrics.bytes_received.load(Ordering::Relaxed);
+            self.rates[i] = (bytes - self.last_bytes[i]) as f64 / secs;
+            self.last_bytes[i] = bytes;
+        }
+        self.last_sample = now;
+    }
+}
+
+impl Placement for LeastBytesPlacement {
+    fn choose(&mut self, workers: &[PixelflutThreadState]) -> usize {
+        self.sample(workers);
+        let i = argmin(
+            self.rates
+                .iter()
+                .copied()
+                .zip(self.connections.open_connections(workers)),
+        );
+        self.connections.handed_off[i] += 1;
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_placement, LeastBytesPlacement, Placement};
+    use crate::core::{
+        config::{Config, PlacementStrategy},
+        game::PixelflutGame,
+        metrics::count,
+    };
+    use std::time::Duration;
+
+    const WORKERS: usize = 4;
+
+    fn game() -> &'static PixelflutGame {
+        PixelflutGame::new(&Config {
+            num_io_threads: WORKERS,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// Every 4th client stays connected, all others disconnect right away. Returns the open connections per worker.
+    fn skewed_lifetimes(mut placement: Box<dyn Placement + Send>) -> Vec<u64> {
+        let workers = game().workers();
+        let mut open = vec![0; WORKERS];
+        for client in 0..400 {
+            let i = placement.choose(workers);
+            if client % 4 == 0 {
+                open[i] += 1;
+            } else {
+                count(&workers[i].metrics.handoffs_closed, 1);
+            }
+        }
+        open
+    }
+
+    /// Every 8th client sends 100 times as much as the others. Returns the bytes per tick each worker receives.
+    fn skewed_rates(mut placement: Box<dyn Placement + Send>) -> Vec<u64> {
+        let workers = game().workers();
+        let mut rates = vec![0; WORKERS];
+        for client in 0..64 {
+            for (worker, &rate) in workers.iter().zip(&rates) {
+                count(&worker.metrics.bytes_received, rate);
+            }
+            let i = placement.choose(workers);
+            rates[i] += if client % 8 == 0 { 100 } else { 1 };
+        }
+        rates
+    }
+
+    fn spread(load: &[u64]) -> u64 {
+        load.iter().max().unwrap() - load.iter().min().unwrap()
+    }
+
+    #[test]
+    fn test_least_connections() {
+        // Round robin puts every long-lived client onto the same thread
+        let open = skewed_lifetimes(new_placement(PlacementStrategy::RoundRobin, WORKERS));
+        assert_eq!(open, [100, 0, 0, 0]);
+
+        let open = skewed_lifetimes(new_placement(PlacementStrategy::LeastConnections, WORKERS));
+        assert_eq!(open, [25, 25, 25, 25]);
+    }
+
+    #[test]
+    fn test_least_bytes() {
+        let rates = skewed_rates(new_placement(PlacementStrategy::RoundRobin, WORKERS));
+        assert_eq!(rates, [808, 16, 16, 16]);
+
+        let rates = skewed_rates(Box::new(LeastBytesPlacement::new(WORKERS, Duration::ZERO)));
+        assert!(spread(&rates) <= 100, "unbalanced: {rates:?}");
+        assert_eq!(rates.iter().sum::<u64>(), 856);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    sync::{Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::{Handle, Signals},
+};
+
+/// Server-wide shutdown notification, usable from both async tasks and blocking threads
+pub struct Shutdown {
+    triggered: Mutex<bool>,
+    condvar: Condvar,
+    // Nothing is ever sent; closing the channel wakes up all async waiters at once
+    tx: async_channel::Sender<()>,
+    rx: async_channel::Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = async_channel::bounded(1);
+        Self {
+            triggered: Mutex::new(false),
+            condvar: Condvar::new(),
+            tx,
+            rx,
+        }
+    }
+
+    pub fn trigger(&self) {
+        *self.triggered.lock().unwrap() = true;
+        self.condvar.notify_all();
+        self.tx.close();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.lock().unwrap()
+    }
+
+    pub async fn wait(&self) {
+        let _ = self.rx.recv().await;
+    }
+
+    /// Block until shutdown or until `timeout` elapses. Returns true on shutdown.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let triggered = self.triggered.lock().unwrap();
+        let (triggered, _) = self
+            .condvar
+            .wait_timeout_while(triggered, timeout, |triggered| !*triggered)
+            .unwrap();
+        *triggered
+    }
+
+    pub fn wait_blocking(&self) {
+        let triggered = self.triggered.lock().unwrap();
+        drop(
+            self.condvar
+                .wait_while(triggered, |triggered| !*triggered)
+                .unwrap(),
+        );
+    }
+}
+
+/// Watches for SIGINT and SIGTERM on a separate thread
+pub struct SignalHandler {
+    handle: Handle,
+    thread: JoinHandle<()>,
+}
+
+impl SignalHandler {
+    /// Stop watching for signals and join the thread
+    pub fn close(self) {
+        self.handle.close();
+        self.thread.join().unwrap();
+    }
+}
+
+/// Trigger `shutdown` on SIGINT or SIGTERM. A second signal exits immediately, in case shutting down hangs.
+pub fn install_signal_handler(shutdown: &'static Shutdown) -> SignalHandler {
+    let mut signals = Signals::new([SIGINT, SIGTERM]).expect("Failed to register signal handlers");
+    let handle = signals.handle();
+    let thread = thread::Builder::new()
+        .name("Signals".to_owned())
+        .spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_triggered() {
+                    eprintln!("Received signal {signal} again, exiting immediately");
+                    std::process::exit(1);
+                }
+                eprintln!("Received signal {signal}, shutting down");
+                shutdown.trigger();
+            }
+        })
+        .expect("Spawn Signal Thread");
+    SignalHandler { handle, thread }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.wait_timeout(Duration::from_millis(1)));
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| futures::executor::block_on(shutdown.wait()));
+            shutdown.trigger();
+            waiter.join().unwrap();
+        });
+        assert!(shutdown.wait_timeout(Duration::from_secs(60)));
+        shutdown.wait_blocking();
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+use image::{imageops, ImageFormat, RgbaImage};
+
+use super::{
+    config::{CanvasFit, SnapshotConfig},
+    image::{Coord, PixelflutImage},
+    shutdown::Shutdown,
+};
+
+#[derive(Debug)]
+pub struct SnapshotError {
+    pub path: String,
+    pub error: image::ImageError,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "snapshot '{}': {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+fn scanout_rgba(image: &PixelflutImage) -> RgbaImage {
+    let mut data = vec![0u8; image.scanout_size()];
+    image.scanout(&mut data);
+    RgbaImage::from_raw(image.width, image.height, data).unwrap()
+}
+
+/// Write the canvas to `path`, replacing it atomically.
+pub fn write_snapshot(image: &PixelflutImage, path: &str) -> Result<(), SnapshotError> {
+    let wrap = |error| SnapshotError {
+        path: path.to_owned(),
+        error,
+    };
+    let format = ImageFormat::from_path(path).map_err(wrap)?;
+    let rgba = scanout_rgba(image);
+
+    // Write to a temporary file next to the target and rename it over, so a crash never leaves a torn snapshot
+    let tmp_path = format!("{path}.tmp");
+    let write_tmp = || -> image::ImageResult<()> {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        rgba.write_to(&mut writer, format)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    };
+    write_tmp().map_err(wrap)?;
+    fs::rename(&tmp_path, path).map_err(|e| wrap(e.into()))
+}
+
+/// Load an image file as a canvas of the given dimensions, fitting it according to `fit`.
+pub fn load_canvas(
+    path: &str,
+    width: Coord,
+    height: Coord,
+    fit: CanvasFit,
+) -> Result<PixelflutImage, SnapshotError> {
+    let loaded = image::open(path)
+        .map_err(|error| SnapshotError {
+            path: path.to_owned(),
+            error,
+        })?
+        .into_rgba8();
+
+    let canvas = if loaded.dimensions() == (width, height) {
+        loaded
+    } else {
+        match fit {
+            CanvasFit::Crop => {
+                let mut canvas = RgbaImage::from_pixel(width, height, image::Rgba([0, 0, 0, 0xFF]));
+                imageops::replace(&mut canvas, &loaded, 0, 0);
+                canvas
+            }
+            CanvasFit::Scale => {
+                imageops::resize(&loaded, width, height, imageops::FilterType::Triangle)
+            }
+        }
+    };
+
+    // The canvas is always opaque
+    let mut data = canvas.into_raw();
+    for px in data.chunks_exact_mut(4) {
+        px[3] = 0xFF;
+    }
+    Ok(PixelflutImage::new_from_rgba(width, height, &data))
+}
+
+/// The image to start with, if any, as configured by `config`
+pub fn initial_canvas(
+    config: &SnapshotConfig,
+    width: Coord,
+    height: Coord,
+) -> Result<Option<PixelflutImage>, SnapshotError> {
+    let path = match (&config.initial_canvas, &config.path) {
+        (Some(initial), _) => initial,
+        (None, Some(snapshot)) if config.restore && Path::new(snapshot).exists() => snapshot,
+        _ => return Ok(None),
+    };
+    load_canvas(path, width, height, config.fit).map(Some)
+}
+
+/// Periodically write snapshots of `image`, and a final one once `stopped` is triggered, which must be after the last
+/// write to `image`.
+pub fn snapshot_loop(image: &PixelflutImage, config: &SnapshotConfig, stopped: &Shutdown) {
+    let Some(path) = &config.path else {
+        return;
+    };
+    loop {
+        let stop = stopped.wait_timeout(Duration::from_secs(config.interval_secs));
+        if let Err(e) = write_snapshot(image, path) {
+            eprintln!("error: {e}");
+        }
+        if stop {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_canvas, write_snapshot};
+    use crate::core::{
+        config::CanvasFit,
+        image::{PixelflutImage, RGBAPixel},
+    };
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("pixelflut-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let image = PixelflutImage::new_with(8, 4);
+        image.set_pixel(1, 2, RGBAPixel::new_rgb(0x12, 0x34, 0x56));
+        image.set_pixel(7, 3, RGBAPixel::new_rgb(0xFF, 0, 0));
+
+        for ext in ["png", "qoi"] {
+            let path = dir.join(format!("canvas.{ext}"));
+            let path = path.to_str().unwrap();
+            write_snapshot(&image, path).unwrap();
+
+            let same = load_canvas(path, 8, 4, CanvasFit::Crop).unwrap();
+            for y in 0..4 {
+                for x in 0..8 {
+                    assert_eq!(
+                        same.get_pixel(x, y).into_rgba(),
+                        image.get_pixel(x, y).into_rgba()
+                    );
+                }
+            }
+
+            let cropped = load_canvas(path, 4, 6, CanvasFit::Crop).unwrap();
+            assert_eq!(cropped.get_pixel(1, 2).channels(), [0x12, 0x34, 0x56, 0xFF]);
+            assert_eq!(cropped.get_pixel(3, 5).channels(), [0, 0, 0, 0xFF]);
+
+            let scaled = load_canvas(path, 16, 8, CanvasFit::Scale).unwrap();
+            assert_eq!((scaled.width, scaled.height), (16, 8));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,43 @@
-use super::image::{Coord, PixelflutImage};
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+use super::{
+    image::{Coord, PixelflutImage},
+    journal::{Journal, JournalEntry, JournalRecord},
+    leaderboard::Leaderboard,
+    limits::ClientLimits,
+    metrics::{GlobalMetrics, WorkerMetrics},
+    shutdown::Shutdown,
+    viewer::ViewerFeed,
+};
 
 /// State of each IO-Thread, shared between multiple clients
 pub struct PixelflutThreadState {
     pub global_config: PixelflutGlobalConfig,
     pub global_state: &'static PixelflutGlobalState,
+    pub metrics: WorkerMetrics,
+    /// Encoded journal entries, until the journal thread writes them out
+    pub journal_buffer: Mutex<Vec<u8>>,
+    /// Pixels set per source address, until they are merged into the leaderboard
+    pub pixels_by_ip: Mutex<HashMap<IpAddr, u64>>,
+}
+
+impl PixelflutThreadState {
+    /// Append an entry to the journal, if it is enabled
+    pub fn journal(&self, connection: u32, record: JournalRecord) {
+        let journal = &self.global_state.journal;
+        if journal.is_enabled() {
+            let entry = JournalEntry {
+                time_us: journal.now_us(),
+                connection,
+                record,
+            };
+            entry.encode(&mut self.journal_buffer.lock().unwrap());
+        }
+    }
+
+    pub fn count_pixels_by_ip(&self, ip: IpAddr, pixels: u64) {
+        *self.pixels_by_ip.lock().unwrap().entry(ip).or_default() += pixels;
+    }
 }
 
 /// Configuration shared by all threads
@@ -16,4 +50,12 @@ pub struct PixelflutGlobalConfig {
 /// State of the entire pixelflut core (shared between all threads)
 pub struct PixelflutGlobalState {
     pub image: PixelflutImage,
+    pub limits: ClientLimits,
+    pub shutdown: Shutdown,
+    /// Triggered after shutdown, once the IO threads finished executing the commands of their clients
+    pub io_stopped: Shutdown,
+    pub metrics: GlobalMetrics,
+    pub viewers: ViewerFeed,
+    pub journal: Journal,
+    pub leaderboard: Leaderboard,
 }
\ No newline at end of file
@@ -0,0 +1,129 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+
+use super::{config::ViewerConfig, image::PixelflutImage, shutdown::Shutdown};
+
+// Arc<Vec> rather than Arc<[u8]>, since monoio can only write the former
+pub type JpegFrame = Arc<Vec<u8>>;
+
+/// Distributes encoded frames to the connected viewers
+pub struct ViewerFeed {
+    max_viewers: usize,
+    // A viewer that has not taken the last frame yet just misses the next one
+    viewers: Mutex<Vec<async_channel::Sender<JpegFrame>>>,
+}
+
+impl ViewerFeed {
+    pub fn new(max_viewers: usize) -> Self {
+        Self {
+            max_viewers,
+            viewers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Register a new viewer. Returns None if there are already `max_viewers` viewers.
+    pub fn subscribe(&self) -> Option<async_channel::Receiver<JpegFrame>> {
+        let mut viewers = self.viewers.lock().unwrap();
+        viewers.retain(|tx| !tx.is_closed());
+        if viewers.len() >= self.max_viewers {
+            return None;
+        }
+        let (tx, rx) = async_channel::bounded(1);
+        viewers.push(tx);
+        Some(rx)
+    }
+
+    pub fn has_viewers(&self) -> bool {
+        let mut viewers = self.viewers.lock().unwrap();
+        viewers.retain(|tx| !tx.is_closed());
+        !viewers.is_empty()
+    }
+
+    fn publish(&self, frame: JpegFrame) {
+        self.viewers
+            .lock()
+            .unwrap()
+            .retain(|tx| match tx.try_send(frame.clone()) {
+                Ok(()) | Err(async_channel::TrySendError::Full(_)) => true,
+                Err(async_channel::TrySendError::Closed(_)) => false,
+            });
+    }
+
+    /// Disconnect all viewers
+    fn close(&self) {
+        self.viewers.lock().unwrap().clear();
+    }
+}
+
+pub fn encode_jpeg(image: &PixelflutImage, quality: u8) -> Vec<u8> {
+    let mut rgba = vec![0u8; image.scanout_size()];
+    image.scanout(&mut rgba);
+    // JPEG has no alpha channel, and our canvas is always opaque anyway
+    let rgb: Vec<u8> = rgba
+        .chunks_exact(4)
+        .flat_map(|px| &px[..3])
+        .copied()
+        .collect();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode(&rgb, image.width, image.height, ExtendedColorType::Rgb8)
+        .expect("Encoding to memory cannot fail");
+    jpeg
+}
+
+/// Encode the canvas `config.fps` times per second while anyone is watching, until shutdown.
+pub fn viewer_loop(
+    image: &PixelflutImage,
+    feed: &ViewerFeed,
+    config: &ViewerConfig,
+    shutdown: &Shutdown,
+) {
+    let interval = Duration::from_secs(1) / config.fps;
+    while !shutdown.wait_timeout(interval) {
+        if feed.has_viewers() {
+            feed.publish(encode_jpeg(image, config.quality).into());
+        }
+    }
+    feed.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_jpeg, ViewerFeed};
+    use crate::core::image::{PixelflutImage, RGBAPixel};
+
+    #[test]
+    fn test_encode_jpeg() {
+        let image = PixelflutImage::new_with(40, 30);
+        image.fill_rect(0, 0, 20, 30, RGBAPixel::new_rgb(0xFF, 0xFF, 0xFF));
+        let decoded = image::load_from_memory(&encode_jpeg(&image, 90))
+            .unwrap()
+            .into_rgb8();
+        assert_eq!(decoded.dimensions(), (40, 30));
+        assert!(decoded.get_pixel(5, 5).0.iter().all(|&c| c > 0xF0));
+        assert!(decoded.get_pixel(35, 5).0.iter().all(|&c| c < 0x10));
+    }
+
+    #[test]
+    fn test_max_viewers() {
+        let feed = ViewerFeed::new(2);
+        let first = feed.subscribe().unwrap();
+        let second = feed.subscribe().unwrap();
+        assert!(feed.subscribe().is_none());
+
+        feed.publish(vec![1].into());
+        feed.publish(vec![2].into());
+        assert_eq!(*first.try_recv().unwrap(), [1]);
+        assert!(first.try_recv().is_err());
+
+        drop(second);
+        assert!(feed.subscribe().is_some());
+        feed.close();
+        assert!(first.try_recv().is_err() && first.is_closed());
+    }
+}
@@ -1,23 +1,47 @@
-use crate::core::{config::Config, game::PixelflutGame, image::PixelflutImage};
+use crate::core::{
+    config::{Config, Encoder, RecordingConfig},
+    game::PixelflutGame,
+    image::PixelflutImage,
+    metrics::count,
+    overlay::{Overlay, OVERLAY_INTERVAL},
+};
 use glib::{object::ObjectExt, SourceId};
 use gstreamer::{
     glib::object::Cast,
-    prelude::{ElementExt, ElementExtManual, GstBinExt},
+    prelude::{ElementExt, ElementExtManual, GstBinExt, GstObjectExt},
+    MessageType, StateChangeError,
 };
 use gstreamer_app::{AppSrc, AppSrcCallbacks, AppStreamType};
 use std::{
+    error::Error,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
-pub fn gstreamer_pipeline(config: &Config, game: &'static PixelflutGame) {
-    gstreamer::init().unwrap();
+pub fn gstreamer_pipeline(
+    config: &Config,
+    game: &'static PixelflutGame,
+) -> Result<(), Box<dyn Error>> {
+    gstreamer::init()?;
     let mainloop = glib::MainLoop::new(None, true);
 
-    let pipeline = gstreamer::parse::launch("appsrc block=true do-timestamp=true is-live=true name=input ! videoconvert ! tee name=branch")
+    let overlay = if config.overlay.enabled {
+        if gstreamer::ElementFactory::find("textoverlay").is_none() {
+            return Err(
+                "the overlay needs the textoverlay element (gst-plugins-base pango)".into(),
+            );
+        }
+        let position = config.overlay.position;
+        let valignment = if position.is_top() { "top" } else { "bottom" };
+        let halignment = if position.is_left() { "left" } else { "right" };
+        format!("textoverlay name=overlay shaded-background=true valignment={valignment} halignment={halignment} ! ")
+    } else {
+        String::new()
+    };
+    let pipeline = gstreamer::parse::launch(&format!("appsrc block=true format=time is-live=true name=input ! videoconvert ! {overlay}tee name=branch"))
         .expect("Failed to create pipeline");
     let pipeline: gstreamer::Bin = pipeline.downcast().unwrap();
-    bus_dispatcher(&pipeline);
+    bus_dispatcher(&pipeline, &mainloop);
     let appsrc: AppSrc = pipeline.by_name("input").unwrap().downcast().unwrap();
     let tee = pipeline.by_name("branch").unwrap();
 
@@ -28,17 +52,38 @@ pub fn gstreamer_pipeline(config: &Config, game: &'static PixelflutGame) {
                     .field("format", gstreamer_video::VideoFormat::Rgba.to_str())
                     .field("width", game.image().width as i32)
                     .field("height", game.image().height as i32)
+                    .field(
+                        "framerate",
+                        gstreamer::Fraction::new(config.gst_fps as i32, 1),
+                    )
                     .build(),
             )
             .build(),
     ));
     appsrc.set_stream_type(AppStreamType::Stream); // push-mode
-    appsrc_handler(&appsrc, |appsrc| {
-        println!("Meow");
-        let buffer = scanout_image(game.image());
-        appsrc.push_buffer(buffer).unwrap();
+    let mut frames = FrameProducer::new(config.gst_fps);
+    appsrc_handler(&appsrc, config.gst_fps, move |appsrc| {
+        let Some(buffer) = frames.next_frame(appsrc, game.image()) else {
+            return;
+        };
+        // This fails after EOS, when we are shutting down anyway
+        if appsrc.push_buffer(buffer).is_ok() {
+            count(&game.metrics().frames_pushed, 1);
+        }
     });
 
+    if let Some(textoverlay) = pipeline.by_name("overlay") {
+        let mut overlay = Overlay::new(config);
+        textoverlay.set_property("text", overlay.text(game));
+        glib::timeout_add(OVERLAY_INTERVAL, move || {
+            textoverlay.set_property("text", overlay.text(game));
+            glib::ControlFlow::Continue
+        });
+    }
+
+    // Every branch with a name for errors
+    let mut branches = Vec::new();
+
     if config.gst_window {
         let videobranch =
             gstreamer::parse::bin_from_description("queue ! videoconvert ! autovideosink sync=false", true)
@@ -46,40 +91,280 @@ pub fn gstreamer_pipeline(config: &Config, game: &'static PixelflutGame) {
 
         pipeline.add(&videobranch).unwrap();
         tee.link(&videobranch).unwrap();
+        branches.push(("the window".to_owned(), videobranch));
     }
 
     if let Some(ref recordingfile) = config.record_to_file {
-        // We need matroska because there is no graceful shutdown
-        let recordingbranch = gstreamer::parse::bin_from_description(
-            "queue ! videoconvert ! vah264enc ! h264parse ! matroskamux ! filesink name=file",
-            true,
-        )
-        .expect("Recording branch");
-        let filesink = recordingbranch.by_name("file").unwrap();
-
-        filesink.set_property("location", recordingfile);
-
+        // On shutdown, we send EOS, so muxers that need finalizing (like mp4mux) work too
+        let recordingbranch = recording_branch(recordingfile, &config.recording)?;
         pipeline.add(&recordingbranch).unwrap();
         tee.link(&recordingbranch).unwrap();
+        branches.push((
+            format!("the recording to '{recordingfile}'"),
+            recordingbranch,
+        ));
+    }
+
+    for description in &config.gst_branches {
+        // Without a queue, a slow branch would hold up all others
+        let branch =
+            gstreamer::parse::bin_from_description(&format!("queue ! {description}"), true)
+                .map_err(|e| format!("invalid gst_branches entry '{description}': {e}"))?;
+        pipeline.add(&branch).unwrap();
+        tee.link(&branch)
+            .map_err(|e| format!("cannot attach gst_branches entry '{description}': {e}"))?;
+        branches.push((format!("gst_branches entry '{description}'"), branch));
+    }
+
+    if let Err(e) = pipeline.set_state(gstreamer::State::Playing) {
+        let error = start_error(&pipeline, &branches, e);
+        let _ = pipeline.set_state(gstreamer::State::Null);
+        return Err(error.into());
     }
 
-    pipeline.set_state(gstreamer::State::Playing).unwrap();
+    // Send EOS on shutdown and wait for it to reach the sinks (see bus_dispatcher), so that recordings are
+    // finalized. If that never happens (e.g. there are no sinks), give up after a timeout.
+    let eos_thread = std::thread::Builder::new()
+        .name("GStreamer EOS".to_owned())
+        .spawn({
+            let appsrc = appsrc.clone();
+            let mainloop = mainloop.clone();
+            move || {
+                game.shutdown().wait_blocking();
+                let _ = appsrc.end_of_stream();
+                glib::timeout_add_once(EOS_TIMEOUT, move || {
+                    eprintln!("Timed out waiting for EOS");
+                    mainloop.quit();
+                });
+            }
+        })
+        .expect("Spawn EOS Thread");
+
     mainloop.run();
+    pipeline.set_state(gstreamer::State::Null).unwrap();
+    eos_thread.join().unwrap();
+    Ok(())
+}
+
+const EOS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Container format of the recording, by file extension
+#[derive(Clone, Copy)]
+enum Container {
+    Mp4,
+    WebM,
+    Matroska,
 }
 
-fn scanout_image(image: &PixelflutImage) -> gstreamer::Buffer {
-    let mut buffer = gstreamer::Buffer::new();
+impl Container {
+    fn for_path(path: &str) -> Container {
+        if path.ends_with(".mp4") {
+            Container::Mp4
+        } else if path.ends_with(".webm") {
+            Container::WebM
+        } else {
+            Container::Matroska
+        }
+    }
+
+    fn muxer(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4mux",
+            Container::WebM => "webmmux",
+            Container::Matroska => "matroskamux",
+        }
+    }
+
+    fn supports(self, encoder: Encoder) -> bool {
+        match self {
+            Container::Mp4 => !matches!(encoder, Encoder::Vp8 | Encoder::Ffv1),
+            Container::WebM => matches!(encoder, Encoder::Vp8 | Encoder::SvtAv1 | Encoder::Rav1e),
+            Container::Matroska => true,
+        }
+    }
+}
+
+/// How to instantiate an [Encoder]
+struct EncoderElement {
+    encoder: Encoder,
+    factory: &'static str,
+    /// The bitrate property, and how many of its units make up a kbit/s
+    bitrate: Option<(&'static str, u64)>,
+    preset: Option<&'static str>,
+    /// Needed between the encoder and the muxer
+    parser: Option<&'static str>,
+}
+
+impl EncoderElement {
+    fn of(encoder: Encoder) -> EncoderElement {
+        let (factory, bitrate, preset, parser) = match encoder {
+            Encoder::Vah264 => (
+                "vah264enc",
+                Some(("bitrate", 1)),
+                Some("target-usage"),
+                Some("h264parse"),
+            ),
+            Encoder::X264 => (
+                "x264enc",
+                Some(("bitrate", 1)),
+                Some("speed-preset"),
+                Some("h264parse"),
+            ),
+            Encoder::OpenH264 => (
+                "openh264enc",
+                Some(("bitrate", 1000)),
+                Some("complexity"),
+                Some("h264parse"),
+            ),
+            Encoder::Vp8 => (
+                "vp8enc",
+                Some(("target-bitrate", 1000)),
+                Some("cpu-used"),
+                None,
+            ),
+            Encoder::SvtAv1 => (
+                "svtav1enc",
+                Some(("target-bitrate", 1)),
+                Some("preset"),
+                Some("av1parse"),
+            ),
+            Encoder::Rav1e => (
+                "rav1enc",
+                Some(("bitrate", 1000)),
+                Some("speed-preset"),
+                Some("av1parse"),
+            ),
+            Encoder::Ffv1 => ("avenc_ffv1", None, None, None),
+        };
+        EncoderElement {
+            encoder,
+            factory,
+            bitrate,
+            preset,
+            parser,
+        }
+    }
+
+    /// The encoder as a pipeline description, with the bitrate and preset applied where supported
+    fn description(&self, config: &RecordingConfig) -> String {
+        let mut description = self.factory.to_owned();
+        if let (Some(kbps), Some((property, units))) = (config.bitrate_kbps, self.bitrate) {
+            description += &format!(" {property}={}", kbps as u64 * units);
+        }
+        if let (Some(preset), Some(property)) = (config.presets.get(&self.encoder), self.preset) {
+            description += &format!(" {property}={preset}");
+        }
+        if let Some(parser) = self.parser {
+            description += &format!(" ! {parser}");
+        }
+        description
+    }
+}
+
+/// Build the recording branch with the first configured encoder that is installed and fits the container
+fn recording_branch(
+    path: &str,
+    config: &RecordingConfig,
+) -> Result<gstreamer::Bin, Box<dyn Error>> {
+    let container = Container::for_path(path);
+    let Some(encoder) = config
+        .encoders
+        .iter()
+        .filter(|&&encoder| container.supports(encoder))
+        .map(|&encoder| EncoderElement::of(encoder))
+        .find(|element| gstreamer::ElementFactory::find(element.factory).is_some())
+    else {
+        return Err(format!(
+            "none of the recording encoders {:?} is installed and supports the container of '{path}'",
+            config.encoders
+        )
+        .into());
+    };
+
+    let description = format!(
+        "queue ! videoconvert ! {} ! {} ! filesink name=file",
+        encoder.description(config),
+        container.muxer()
+    );
+    let branch = gstreamer::parse::bin_from_description(&description, true)
+        .map_err(|e| format!("invalid recording branch '{description}': {e}"))?;
+    let filesink = branch.by_name("file").unwrap();
+    filesink.set_property("location", path);
+    println!("Recording to {path} with {}", encoder.factory);
+    Ok(branch)
+}
+
+fn scanout_image(image: &PixelflutImage) -> gstreamer::Memory {
     let mut memory = gstreamer::Memory::with_size(image.scanout_size());
     {
         let mut memory_mapw = memory.get_mut().unwrap().map_writable().unwrap();
         let memory_slice = memory_mapw.as_mut_slice();
         image.scanout(memory_slice);
     }
-    buffer.get_mut().unwrap().append_memory(memory);
-    buffer
+    memory
+}
+
+/// Timestamps frames at a constant rate, and only scans out the image if it changed since the previous frame
+struct FrameProducer {
+    fps: u64,
+    /// Index of the next frame on the fps grid
+    next_frame: u64,
+    /// The generation of the image in the previous frame
+    previous: Option<(u64, gstreamer::Memory)>,
+}
+
+impl FrameProducer {
+    fn new(fps: u32) -> Self {
+        Self {
+            fps: fps as u64,
+            next_frame: 0,
+            previous: None,
+        }
+    }
+
+    fn frame_time(&self, frame: u64) -> gstreamer::ClockTime {
+        gstreamer::ClockTime::from_nseconds(
+            frame * gstreamer::ClockTime::SECOND.nseconds() / self.fps,
+        )
+    }
+
+    /// The next frame, timestamped at the current running time of `appsrc`. Returns None if it is too early for
+    /// the next frame; if we are late, frames are skipped rather than bunched up.
+    fn next_frame(&mut self, appsrc: &AppSrc, image: &PixelflutImage) -> Option<gstreamer::Buffer> {
+        let frame = match appsrc.current_running_time() {
+            Some(time) => time.nseconds() * self.fps / gstreamer::ClockTime::SECOND.nseconds(),
+            None => self.next_frame,
+        };
+        if frame < self.next_frame {
+            return None;
+        }
+        self.next_frame = frame + 1;
+
+        let generation = image.generation();
+        let memory = match &self.previous {
+            // Rescan once per second anyway, since the generation may miss racing writes
+            Some((previous, memory)) if *previous == generation && frame % self.fps != 0 => {
+                memory.clone()
+            }
+            _ => {
+                let memory = scanout_image(image);
+                self.previous = Some((generation, memory.clone()));
+                memory
+            }
+        };
+
+        let mut buffer = gstreamer::Buffer::new();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.append_memory(memory);
+            buffer.set_pts(self.frame_time(frame));
+            buffer.set_duration(self.frame_time(frame + 1) - self.frame_time(frame));
+        }
+        Some(buffer)
+    }
 }
 
-fn appsrc_handler<F: FnMut(&AppSrc) + Send + 'static>(appsrc: &AppSrc, handler: F) {
+fn appsrc_handler<F: FnMut(&AppSrc) + Send + 'static>(appsrc: &AppSrc, fps: u32, handler: F) {
     struct RegStateHandler<F> {
         handler: F,
         timer_source: Option<SourceId>,
@@ -107,7 +392,7 @@ fn appsrc_handler<F: FnMut(&AppSrc) + Send + 'static>(appsrc: &AppSrc, handler:
                     if state_borrow.timer_source.is_none() {
                         let state = state.clone();
                         state_borrow.timer_source =
-                            Some(glib::timeout_add(Duration::from_millis(15), move || {
+                            Some(glib::timeout_add(Duration::from_secs(1) / fps, move || {
                                 let mut state_borrow = state.handler.lock().unwrap();
                                 (state_borrow.handler)(&state.appsrc);
 
@@ -129,10 +414,39 @@ fn appsrc_handler<F: FnMut(&AppSrc) + Send + 'static>(appsrc: &AppSrc, handler:
     );
 }
 
-fn bus_dispatcher(pipeline: &gstreamer::Bin) {
+/// Why `pipeline` failed to start, naming the branch at fault if it is one of `branches`. The bus still holds the
+/// error, since the main loop that dispatches it isn't running yet.
+fn start_error(
+    pipeline: &gstreamer::Bin,
+    branches: &[(String, gstreamer::Bin)],
+    e: StateChangeError,
+) -> String {
+    let message = pipeline
+        .bus()
+        .and_then(|bus| bus.pop_filtered(&[MessageType::Error]));
+    let Some(message) = message else {
+        return format!("failed to start the pipeline: {e}");
+    };
+    let gstreamer::MessageView::Error(error) = message.view() else {
+        unreachable!("filtered for errors");
+    };
+    let branch = branches
+        .iter()
+        .find(|(_, branch)| message.src().is_some_and(|src| src.has_as_ancestor(branch)));
+    match branch {
+        Some((name, _)) => format!("{name} failed to start: {}", error.error()),
+        None => format!("failed to start the pipeline: {}", error.error()),
+    }
+}
+
+fn bus_dispatcher(pipeline: &gstreamer::Bin, mainloop: &glib::MainLoop) {
     let bus = pipeline.bus().unwrap();
     bus.add_signal_watch();
-    bus.connect_message(None, |_bus, message| match message.view() {
+    let mainloop = mainloop.clone();
+    bus.connect_message(None, move |_bus, message| match message.view() {
+        gstreamer::MessageView::Eos(_) => {
+            mainloop.quit();
+        }
         gstreamer::MessageView::Error(error) => {
             let e = error.debug().unwrap();
             println!("Error: {e}");
@@ -148,3 +462,124 @@ fn bus_dispatcher(pipeline: &gstreamer::Bin) {
         _ => {}
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{gstreamer_pipeline, recording_branch, EncoderElement};
+    use crate::core::{
+        config::{Config, Encoder, RecordingConfig},
+        game::PixelflutGame,
+        image::RGBAPixel,
+    };
+    use std::{sync::atomic::Ordering, thread, time::Duration};
+
+    #[test]
+    fn test_custom_branches() {
+        let mut config = Config {
+            image_width: 64,
+            image_height: 32,
+            gst_window: false,
+            gst_branches: vec!["no-such-element".to_owned()],
+            ..Default::default()
+        };
+        let game = PixelflutGame::new(&config).unwrap();
+        let error = gstreamer_pipeline(&config, game).unwrap_err();
+        assert!(error.to_string().contains("no-such-element"), "{error}");
+
+        // Parses, but cannot start
+        let unwritable = "filesink location=/nonexistent/dir/out.raw";
+        config.gst_branches = vec![unwritable.to_owned()];
+        let game = PixelflutGame::new(&config).unwrap();
+        let error = gstreamer_pipeline(&config, game).unwrap_err();
+        assert!(error.to_string().contains(unwritable), "{error}");
+
+        config.gst_branches = vec!["videoconvert ! fakesink".to_owned()];
+        let game = PixelflutGame::new(&config).unwrap();
+        let stop = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            game.shutdown().trigger();
+        });
+        gstreamer_pipeline(&config, game).unwrap();
+        stop.join().unwrap();
+        assert!(game.metrics().frames_pushed.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_recording_fallback() {
+        gstreamer::init().unwrap();
+        // VP8 does not fit into mp4, whether it is installed or not
+        let config = RecordingConfig {
+            encoders: vec![Encoder::Vp8],
+            ..Default::default()
+        };
+        assert!(recording_branch("out.mp4", &config).is_err());
+    }
+
+    #[test]
+    fn test_encoder_presets() {
+        let config = RecordingConfig {
+            bitrate_kbps: Some(2000),
+            presets: [(Encoder::X264, "ultrafast".to_owned())].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            EncoderElement::of(Encoder::X264).description(&config),
+            "x264enc bitrate=2000 speed-preset=ultrafast ! h264parse"
+        );
+        // Falling back to another encoder must not apply the preset of x264 to it
+        assert_eq!(
+            EncoderElement::of(Encoder::Rav1e).description(&config),
+            "rav1enc bitrate=2000000 ! av1parse"
+        );
+    }
+
+    #[test]
+    fn test_recording() {
+        gstreamer::init().unwrap();
+        // Hardware encoders are unlikely to be available where tests run
+        let encoders: Vec<Encoder> = [
+            Encoder::X264,
+            Encoder::OpenH264,
+            Encoder::Vp8,
+            Encoder::Ffv1,
+        ]
+        .into_iter()
+        .filter(|&encoder| {
+            gstreamer::ElementFactory::find(EncoderElement::of(encoder).factory).is_some()
+        })
+        .collect();
+        if encoders.is_empty() {
+            eprintln!("No software encoder installed, skipping");
+            return;
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("pixelflut-recording-{}.mkv", std::process::id()));
+        let config = Config {
+            image_width: 64,
+            image_height: 32,
+            gst_window: false,
+            record_to_file: Some(path.to_str().unwrap().to_owned()),
+            recording: RecordingConfig {
+                encoders,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let game = PixelflutGame::new(&config).unwrap();
+        game.image()
+            .fill_rect(0, 0, 32, 32, RGBAPixel::new_rgb(0xff, 0, 0));
+        let stop = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            game.shutdown().trigger();
+        });
+        gstreamer_pipeline(&config, game).unwrap();
+        stop.join().unwrap();
+
+        let recorded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(game.metrics().frames_pushed.load(Ordering::Relaxed) > 0);
+        // The EBML header that every matroska file starts with
+        assert!(recorded.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]));
+    }
+}
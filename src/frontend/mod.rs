@@ -1,2 +1,4 @@
+#[cfg(feature = "winit")]
 pub mod winit;
+#[cfg(feature = "gstreamer")]
 pub mod gstreamer;
\ No newline at end of file
@@ -1,58 +1,487 @@
-    use crate::core::{config::Config, game::PixelflutGame};
-use std::{cmp::min, num::NonZeroU32};
-use winit::{dpi::PhysicalSize, event::Event, event_loop::EventLoopBuilder};
-
-pub fn winit_window_loop(config: &Config, game: &'static PixelflutGame) {
-    let event_loop = EventLoopBuilder::new()
-        .build()
-        .expect("Failed to init event loop");
+use crate::core::{
+    config::{Config, OverlayPosition},
+    game::PixelflutGame,
+    image::{PixelflutImage, RGBAPixel},
+    overlay::{Overlay, OVERLAY_INTERVAL},
+};
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{Dimensions, OriginDimensions, Point, Size},
+    mono_font::{ascii::FONT_10X20, MonoTextStyleBuilder},
+    pixelcolor::{Rgb888, RgbColor},
+    text::{Baseline, Text},
+    transform::Transform,
+    Drawable, Pixel,
+};
+use std::{
+    convert::Infallible,
+    error::Error,
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::{ElementState, Event, MouseButton, MouseScrollDelta, WindowEvent},
+    event_loop::{ControlFlow, EventLoopBuilder},
+    keyboard::{Key, NamedKey},
+    window::Fullscreen,
+};
+
+/// Relative to the size at which the whole image fits the window
+const MAX_ZOOM: f64 = 64.0;
+/// Zoom factor per mouse wheel notch
+const ZOOM_STEP: f64 = 1.25;
+/// Pixels of touchpad scrolling that count as one mouse wheel notch
+const PIXELS_PER_NOTCH: f64 = 50.0;
+
+/// Redraw at least this often even if the image seems unchanged, since [PixelflutImage::generation] may miss a write
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Convert a pixel to the 0RGB format softbuffer expects
+fn xrgb(pixel: RGBAPixel) -> u32 {
+    let [r, g, b, _a] = pixel.channels();
+    u32::from_be_bytes([0, r, g, b])
+}
+
+/// Which part of the image is shown, and how it is scaled to the window. Sizes are (width, height).
+#[derive(Clone, Copy, Debug)]
+struct View {
+    image: (u32, u32),
+    zoom: f64,
+    /// The image position at the center of the window
+    center: (f64, f64),
+    /// Bilinear instead of nearest-neighbour scaling
+    smooth: bool,
+}
+
+impl View {
+    fn new(image: (u32, u32)) -> Self {
+        Self {
+            image,
+            zoom: 1.0,
+            center: (image.0 as f64 / 2.0, image.1 as f64 / 2.0),
+            smooth: false,
+        }
+    }
+
+    /// Show the whole image again
+    fn reset(&mut self) {
+        *self = Self {
+            smooth: self.smooth,
+            ..Self::new(self.image)
+        };
+    }
+
+    /// Window pixels per image pixel. At zoom 1, the image fits the window with its aspect ratio preserved.
+    fn scale(&self, window: (u32, u32)) -> f64 {
+        let fit =
+            (window.0 as f64 / self.image.0 as f64).min(window.1 as f64 / self.image.1 as f64);
+        fit * self.zoom
+    }
+
+    /// The image position shown at a window position
+    fn image_position(&self, window: (u32, u32), (x, y): (f64, f64)) -> (f64, f64) {
+        let scale = self.scale(window);
+        (
+            self.center.0 + (x - window.0 as f64 / 2.0) / scale,
+            self.center.1 + (y - window.1 as f64 / 2.0) / scale,
+        )
+    }
+
+    /// Zoom by `factor`, keeping the image position under `cursor` in place
+    fn zoom_at(&mut self, factor: f64, window: (u32, u32), cursor: (f64, f64)) {
+        let before = self.image_position(window, cursor);
+        self.zoom = (self.zoom * factor).clamp(1.0, MAX_ZOOM);
+        let after = self.image_position(window, cursor);
+        self.center.0 += before.0 - after.0;
+        self.center.1 += before.1 - after.1;
+        self.clamp_center();
+    }
+
+    /// Move the image along with the cursor, by a distance in window pixels
+    fn pan(&mut self, window: (u32, u32), (dx, dy): (f64, f64)) {
+        let scale = self.scale(window);
+        self.center.0 -= dx / scale;
+        self.center.1 -= dy / scale;
+        self.clamp_center();
+    }
+
+    /// The image must not be dragged out of sight
+    fn clamp_center(&mut self) {
+        self.center.0 = self.center.0.clamp(0.0, self.image.0 as f64);
+        self.center.1 = self.center.1.clamp(0.0, self.image.1 as f64);
+    }
+
+    /// Whether image pixels map 1:1 to window pixels
+    fn is_identity(&self, window: (u32, u32)) -> bool {
+        self.image == window && self.zoom == 1.0 && self.center == View::new(self.image).center
+    }
+}
+
+/// Where a window pixel samples the image along one axis: interpolate from `lo` towards `hi` by `weight` / 256
+#[derive(Clone, Copy)]
+struct Sample {
+    lo: usize,
+    hi: usize,
+    weight: u32,
+}
+
+impl Sample {
+    /// Sample at `position` (in image pixels) of an axis of `size` pixels. None if it is outside the image.
+    fn at(position: f64, size: u32, smooth: bool) -> Option<Sample> {
+        if !(0.0..size as f64).contains(&position) {
+            return None;
+        }
+        if !smooth {
+            let i = position as usize;
+            return Some(Sample {
+                lo: i,
+                hi: i,
+                weight: 0,
+            });
+        }
+        // Interpolate between the centers of the nearest two pixels
+        let position = (position - 0.5).clamp(0.0, (size - 1) as f64);
+        let lo = position as usize;
+        Some(Sample {
+            lo,
+            hi: (lo + 1).min(size as usize - 1),
+            weight: ((position - lo as f64) * 256.0) as u32,
+        })
+    }
+}
+
+/// Bilinear interpolation of `image`
+fn interpolate(image: &PixelflutImage, x: Sample, y: Sample) -> u32 {
+    let pixel = |x: usize, y: usize| image.get_pixel(x as u32, y as u32).channels();
+    let [top_left, top_right] = [pixel(x.lo, y.lo), pixel(x.hi, y.lo)];
+    let [bottom_left, bottom_right] = [pixel(x.lo, y.hi), pixel(x.hi, y.hi)];
+    let lerp = |a: u32, b: u32, weight: u32| a * (256 - weight) + b * weight;
+    let mut rgb = [0; 3];
+    for (c, value) in rgb.iter_mut().enumerate() {
+        let top = lerp(top_left[c] as u32, top_right[c] as u32, x.weight);
+        let bottom = lerp(bottom_left[c] as u32, bottom_right[c] as u32, x.weight);
+        *value = ((lerp(top, bottom, y.weight) + (1 << 15)) >> 16) as u8;
+    }
+    u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]])
+}
+
+/// Draw `image` into `buffer` as seen through `view`, with black bars where the image doesn't reach
+fn render(view: &View, image: &PixelflutImage, buffer: &mut [u32], window: (u32, u32)) {
+    let window_width = window.0 as usize;
+    if view.is_identity(window) {
+        // softbuffer wants 0RGB, so the rows cannot be copied verbatim, but converting them is just as cache friendly
+        for (y, dest) in buffer.chunks_exact_mut(window_width).enumerate() {
+            for (dest, pixel) in dest.iter_mut().zip(image.row(y as u32)) {
+                *dest = xrgb(pixel);
+            }
+        }
+        return;
+    }
+
+    // Sample at the centers of the window pixels
+    let columns: Vec<Option<Sample>> = (0..window.0)
+        .map(|x| {
+            let (ix, _) = view.image_position(window, (x as f64 + 0.5, 0.0));
+            Sample::at(ix, view.image.0, view.smooth)
+        })
+        .collect();
+    for (y, row) in buffer.chunks_exact_mut(window_width).enumerate() {
+        let (_, iy) = view.image_position(window, (0.0, y as f64 + 0.5));
+        let Some(sy) = Sample::at(iy, view.image.1, view.smooth) else {
+            row.fill(0);
+            continue;
+        };
+        for (dest, column) in row.iter_mut().zip(&columns) {
+            *dest = match *column {
+                None => 0,
+                Some(sx) if view.smooth => interpolate(image, sx, sy),
+                Some(sx) => xrgb(image.get_pixel(sx.lo as u32, sy.lo as u32)),
+            };
+        }
+    }
+}
+
+/// Margin between the overlay and the edges of the window, in pixels
+const OVERLAY_MARGIN: i32 = 8;
+
+/// The window buffer as a target for drawing the overlay
+struct Canvas<'a> {
+    buffer: &'a mut [u32],
+    size: (u32, u32),
+}
+
+impl OriginDimensions for Canvas<'_> {
+    fn size(&self) -> Size {
+        Size::new(self.size.0, self.size.1)
+    }
+}
+
+impl DrawTarget for Canvas<'_> {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I: IntoIterator<Item = Pixel<Rgb888>>>(
+        &mut self,
+        pixels: I,
+    ) -> Result<(), Infallible> {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y))
+                && x < self.size.0
+                && y < self.size.1
+            {
+                self.buffer[(y * self.size.0 + x) as usize] =
+                    u32::from_be_bytes([0, color.r(), color.g(), color.b()]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Draw `text` in white on black into a corner of the window
+fn draw_overlay(text: &str, position: OverlayPosition, buffer: &mut [u32], window: (u32, u32)) {
+    let style = MonoTextStyleBuilder::new()
+        .font(&FONT_10X20)
+        .text_color(Rgb888::WHITE)
+        .background_color(Rgb888::BLACK)
+        .build();
+    let text = Text::with_baseline(text, Point::zero(), style, Baseline::Top);
+    let size = text.bounding_box().size;
+    let x = match position.is_left() {
+        true => OVERLAY_MARGIN,
+        false => window.0 as i32 - size.width as i32 - OVERLAY_MARGIN,
+    };
+    let y = match position.is_top() {
+        true => OVERLAY_MARGIN,
+        false => window.1 as i32 - size.height as i32 - OVERLAY_MARGIN,
+    };
+    let mut canvas = Canvas {
+        buffer,
+        size: window,
+    };
+    let _ = text.translate(Point::new(x, y)).draw(&mut canvas);
+}
+
+/// Show the canvas in a window at up to `winit_fps` (frames where neither the canvas nor the view changed are
+/// skipped), until shutdown or until the window is closed, which triggers shutdown. Scroll to zoom, drag to pan, '0'
+/// to reset the view, 's' to toggle smooth scaling, 'f' or F11 to toggle fullscreen.
+pub fn winit_window_loop(
+    config: &Config,
+    game: &'static PixelflutGame,
+) -> Result<(), Box<dyn Error>> {
+    let event_loop = EventLoopBuilder::new().build()?;
     let window = winit::window::WindowBuilder::new()
         .with_inner_size(PhysicalSize::new(config.image_width, config.image_height))
         .with_title("Pixelflut (Monoio)")
-        .build(&event_loop)
-        .expect("Failed to create window");
-    let window_ctx = softbuffer::Context::new(&window).expect("Failed to initialize softbuffer");
-    let mut window_surface =
-        softbuffer::Surface::new(&window_ctx, &window).expect("Failed to create surface");
-
-    event_loop
-        .run(|event, _window| {
-            if let Event::WindowEvent {
-                event: winit::event::WindowEvent::RedrawRequested,
-                window_id,
-            } = event
-                && window_id == window.id()
-            {
-                // 1. Acquire buffer we can draw to
-                let PhysicalSize {
-                    width: window_width,
-                    height: window_height,
-                } = window.inner_size();
+        .build(&event_loop)?;
+    let window_ctx = softbuffer::Context::new(&window)?;
+    let mut window_surface = softbuffer::Surface::new(&window_ctx, &window)?;
+
+    let image = game.image();
+    let mut view = View::new((image.width, image.height));
+    // Set when the window needs a redraw even if the image did not change
+    let mut view_changed = true;
+    let mut shown_generation = None;
+    let mut next_refresh = Instant::now();
+    let mut cursor = (0.0, 0.0);
+    let mut dragging = false;
+    let mut overlay = config.overlay.enabled.then(|| Overlay::new(config));
+    let mut overlay_text = String::new();
+    let mut next_overlay = Instant::now();
+    let frame_interval = Duration::from_secs(1) / config.winit_fps;
+    let mut next_frame = Instant::now();
+
+    event_loop.run(|event, target| {
+        if let Event::AboutToWait = event {
+            // Shutdown may also come from a signal or the server
+            if game.shutdown().is_triggered() {
+                target.exit();
+                return;
+            }
+            let now = Instant::now();
+            if now >= next_frame {
+                let changed = shown_generation != Some(image.generation());
+                if changed || view_changed || now >= next_refresh {
+                    window.request_redraw();
+                }
+                next_frame += frame_interval;
+                // Skip the frames we are late for, rather than catching up
+                if next_frame < now {
+                    next_frame = now + frame_interval;
+                }
+            }
+            target.set_control_flow(ControlFlow::WaitUntil(next_frame));
+            return;
+        }
+        let Event::WindowEvent { event, window_id } = event else {
+            return;
+        };
+        if window_id != window.id() {
+            return;
+        }
+        let PhysicalSize { width, height } = window.inner_size();
+        let window_size = (width, height);
+
+        match event {
+            WindowEvent::RedrawRequested => {
+                // Nothing to draw while minimized
+                let (Some(width), Some(height)) = (NonZeroU32::new(width), NonZeroU32::new(height))
+                else {
+                    return;
+                };
                 window_surface
-                    .resize(
-                        NonZeroU32::new(window_width).unwrap(),
-                        NonZeroU32::new(window_height).unwrap(),
-                    )
+                    .resize(width, height)
                     .expect("Resize Surface??");
                 let mut buffer = window_surface.buffer_mut().unwrap();
-
-                // TODO: maybe handle the fast path where width == width && height == height? Then we denegerate to memcpy. Maybe we can also just use gstreamer
-                // and be done with it.
-                // 2. Acquire an image from pixelflut
-                let current_image = game.image();
-                for y in 0..min(current_image.height, window_height) {
-                    for x in 0..min(current_image.width, window_width) {
-                        // FIXME: is this cast sound?
-                        let i_buffer = window_width * y + x;
-                        buffer[i_buffer as usize] = current_image.get_pixel(x, y).into_rgba();
+                // Before rendering, so that writes during it show up in the next frame
+                shown_generation = Some(image.generation());
+                view_changed = false;
+                next_refresh = Instant::now() + REFRESH_INTERVAL;
+                render(&view, image, &mut buffer, window_size);
+                if let Some(overlay) = &mut overlay {
+                    if Instant::now() >= next_overlay {
+                        overlay_text = overlay.text(game);
+                        next_overlay = Instant::now() + OVERLAY_INTERVAL;
                     }
+                    draw_overlay(
+                        &overlay_text,
+                        config.overlay.position,
+                        &mut buffer,
+                        window_size,
+                    );
                 }
 
-                // 3. Display
                 window.pre_present_notify();
-                window.request_redraw();
                 buffer.present().expect("Present buffer");
             }
-        })
-        .expect("Event loop");
+            WindowEvent::CloseRequested => {
+                game.shutdown().trigger();
+                target.exit();
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let notches = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y as f64,
+                    MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => {
+                        y / PIXELS_PER_NOTCH
+                    }
+                };
+                view.zoom_at(ZOOM_STEP.powf(notches), window_size, cursor);
+                view_changed = true;
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                if dragging {
+                    view.pan(window_size, (position.x - cursor.0, position.y - cursor.1));
+                    view_changed = true;
+                }
+                cursor = (position.x, position.y);
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Left,
+                ..
+            } => dragging = state == ElementState::Pressed,
+            WindowEvent::KeyboardInput { event, .. } if event.state == ElementState::Pressed => {
+                match event.logical_key.as_ref() {
+                    Key::Named(NamedKey::F11) | Key::Character("f") => {
+                        let fullscreen = match window.fullscreen() {
+                            Some(_) => None,
+                            None => Some(Fullscreen::Borderless(None)),
+                        };
+                        window.set_fullscreen(fullscreen);
+                    }
+                    Key::Named(NamedKey::Escape) => window.set_fullscreen(None),
+                    Key::Character("s") => {
+                        view.smooth = !view.smooth;
+                        view_changed = true;
+                    }
+                    Key::Character("0") => {
+                        view.reset();
+                        view_changed = true;
+                    }
+                    _ => {}
+                }
+            }
+            WindowEvent::Resized(_) => view_changed = true,
+            _ => {}
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{draw_overlay, render, View};
+    use crate::core::{config::OverlayPosition, image::PixelflutImage};
+
+    const RED: [u8; 4] = [0xFF, 0, 0, 0xFF];
+    const BLUE: [u8; 4] = [0, 0, 0xFF, 0xFF];
+
+    #[test]
+    fn test_render_identity() {
+        let image = PixelflutImage::new_from_rgba(2, 2, &[RED, BLUE, BLUE, RED].concat());
+        let mut buffer = [1; 4];
+        render(&View::new((2, 2)), &image, &mut buffer, (2, 2));
+        assert_eq!(buffer, [0xFF0000, 0xFF, 0xFF, 0xFF0000]);
+    }
+
+    #[test]
+    fn test_render_letterboxed() {
+        let image = PixelflutImage::new_from_rgba(2, 1, &[RED, BLUE].concat());
+        let mut buffer = [1; 16];
+        render(&View::new((2, 1)), &image, &mut buffer, (4, 4));
+        #[rustfmt::skip]
+        assert_eq!(buffer, [
+            0, 0, 0, 0,
+            0xFF0000, 0xFF0000, 0xFF, 0xFF,
+            0xFF0000, 0xFF0000, 0xFF, 0xFF,
+            0, 0, 0, 0,
+        ]);
+
+        let mut view = View::new((2, 1));
+        view.smooth = true;
+        render(&view, &image, &mut buffer, (4, 4));
+        assert_eq!(buffer[4], 0xFF0000);
+        assert_eq!(buffer[7], 0xFF);
+        // Between the pixel centers, red fades into blue
+        assert_eq!(buffer[5], 0xBF0040);
+    }
+
+    #[test]
+    fn test_draw_overlay() {
+        let window = (100, 50);
+        let mut buffer = [1; 100 * 50];
+        draw_overlay("1", OverlayPosition::BottomRight, &mut buffer, window);
+        // A single 10x20 glyph, with a margin of 8 pixels
+        let drawn: Vec<usize> = (0..buffer.len()).filter(|&i| buffer[i] != 1).collect();
+        assert_eq!(drawn.len(), 10 * 20);
+        assert_eq!(drawn[0], 22 * 100 + 82);
+        assert!(drawn.iter().any(|&i| buffer[i] == 0xFFFFFF));
+        assert!(drawn.iter().any(|&i| buffer[i] == 0));
+    }
+
+    #[test]
+    fn test_zoom_and_pan() {
+        let window = (200, 100);
+        let mut view = View::new((100, 100));
+        assert_eq!(view.scale(window), 1.0);
+
+        // The image position under the cursor stays in place
+        let cursor = (70.0, 30.0);
+        let before = view.image_position(window, cursor);
+        view.zoom_at(4.0, window, cursor);
+        assert_eq!(view.scale(window), 4.0);
+        assert_eq!(view.image_position(window, cursor), before);
+
+        view.pan(window, (40.0, 0.0));
+        assert_eq!(view.image_position(window, (110.0, 30.0)), before);
+        view.pan(window, (0.0, 1e6));
+        assert_eq!(view.center.1, 0.0);
+
+        view.zoom_at(1e6, window, cursor);
+        assert_eq!(view.zoom, 64.0);
+        view.zoom_at(1e-6, window, cursor);
+        assert_eq!(view.zoom, 1.0);
+        view.reset();
+        assert!(view.is_identity((100, 100)));
+    }
 }
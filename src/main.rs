@@ -1,38 +1,86 @@
 #![feature(let_chains)]
 #![feature(async_closure)]
+#![cfg_attr(test, feature(test))]
+#[cfg(test)]
+extern crate test;
+
 pub mod core;
 pub mod frontend;
 pub mod protocol;
 
-use core::{config::Config, game::PixelflutGame, state::PixelflutThreadState};
+#[cfg(feature = "gstreamer")]
+use core::journal::{replay_loop, JournalReader};
+use core::{
+    config::{AcceptMode, Config, ConfigError, Frontend, ReplayConfig},
+    game::PixelflutGame,
+    journal::{journal_loop, open_journal, reconstruct},
+    leaderboard::leaderboard_loop,
+    limits::ConnectionGuard,
+    metrics::count,
+    placement::{new_placement, Placement},
+    shutdown::install_signal_handler,
+    snapshot::{snapshot_loop, write_snapshot},
+    state::{PixelflutGlobalState, PixelflutThreadState},
+    viewer::viewer_loop,
+};
+#[cfg(feature = "gstreamer")]
 use frontend::gstreamer::gstreamer_pipeline;
+#[cfg(feature = "winit")]
+use frontend::winit::winit_window_loop;
 use futures::StreamExt;
 use monoio::{
     join,
-    net::{TcpListener, TcpStream},
+    net::{udp::UdpSocket, ListenerOpts, TcpListener, TcpStream, UnixListener, UnixStream},
     FusionDriver, RuntimeBuilder,
 };
-use protocol::tcp_pixelflut::{tcp_pixelflut_handler, PixelflutClient};
+use protocol::{
+    http_metrics::metrics_listener,
+    http_viewer::viewer_listener,
+    input::{input_handler, open_input},
+    tcp_pixelflut::{tcp_pixelflut_handler, PixelflutClient},
+    udp_pixelflut::udp_listener,
+    websocket::websocket_handler,
+};
 use std::{
+    error::Error,
     fmt::Display,
+    future::Future,
     io,
-    net::{SocketAddr, ToSocketAddrs},
-    os::fd::{FromRawFd, IntoRawFd, RawFd},
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    os::{
+        fd::{FromRawFd, IntoRawFd, OwnedFd},
+        unix::fs::FileTypeExt,
+    },
+    sync::atomic::AtomicU64,
     thread,
+    time::Duration,
 };
 
+/// The protocol spoken on a listener
+#[derive(Clone, Copy)]
+enum Transport {
+    Pixelflut,
+    WebSocket,
+    /// Pixelflut over a Unix domain socket
+    Unix,
+}
+
 struct AcceptedClient {
-    stream: RawFd,
+    stream: OwnedFd,
+    guard: ConnectionGuard,
+    transport: Transport,
 }
 
 struct ServerCtx {
     thread_spawners: Box<[async_channel::Sender<AcceptedClient>]>,
+    workers: &'static [PixelflutThreadState],
+    placement: Box<dyn Placement + Send>,
 }
 
 impl ServerCtx {
-    /// Spawn a client on a random thread
-    async fn spawn(&self, client: AcceptedClient) -> bool {
-        let i = rand::random_range(0..self.thread_spawners.len());
+    /// Spawn a client on the thread chosen by the placement strategy
+    async fn spawn(&mut self, client: AcceptedClient) -> bool {
+        let i = self.placement.choose(self.workers);
         self.thread_spawners[i].send(client).await.is_ok()
     }
 }
@@ -48,15 +96,51 @@ fn tcp_listener_stream(
     })
 }
 
+/// Bind `what` on every address `addr` resolves to, before the IO threads start, so that a busy address is an error
+/// on startup rather than a panic on an IO thread. Each IO thread can accept on its own handle of the listeners.
+fn bind_tcp(what: &str, addr: &str) -> Result<Vec<std::net::TcpListener>, Box<dyn Error>> {
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve {what} {addr}: {e}"))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("'{addr}' did not resolve to any listen address").into());
+    }
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        let listen = std::net::TcpListener::bind(addr)
+            .map_err(|e| format!("failed to bind {what} {addr}: {e}"))?;
+        listen.set_nonblocking(true)?;
+        println!("Listening on {addr}");
+        listeners.push(listen);
+    }
+    Ok(listeners)
+}
+
+/// Accept on listeners bound by [bind_tcp]
+fn accept_tcp(
+    listeners: Vec<std::net::TcpListener>,
+) -> impl futures::Stream<Item = (TcpStream, SocketAddr)> {
+    futures::stream::select_all(listeners.into_iter().map(|listen| {
+        let listen = TcpListener::from_std(listen).expect("Failed to register listener");
+        Box::pin(tcp_listener_stream(listen))
+    }))
+}
+
+/// Listen on every address `addr` resolves to. With `reuse_port`, every IO thread binds its own listener to the same
+/// port (SO_REUSEPORT); otherwise binding a port that is already in use fails.
 fn tcp_listeners<A: ToSocketAddrs + Display>(
     addr: A,
+    reuse_port: bool,
 ) -> impl futures::Stream<Item = (TcpStream, SocketAddr)> {
+    let opts = ListenerOpts::new().reuse_port(reuse_port);
     let mut listeners = Vec::new();
     let mut listen_on_any = false;
     for addr in addr.to_socket_addrs().unwrap() {
         listen_on_any = true;
         println!("Listening on {addr}");
-        let listen = TcpListener::bind(addr).expect(&format!("failed to bind {addr}"));
+        let listen =
+            TcpListener::bind_with_config(addr, &opts).expect(&format!("failed to bind {addr}"));
         let stream = Box::pin(tcp_listener_stream(listen));
         listeners.push(stream);
     }
@@ -67,12 +151,203 @@ fn tcp_listeners<A: ToSocketAddrs + Display>(
     futures::stream::select_all(listeners)
 }
 
-async fn tcp_listener<A: ToSocketAddrs + Display>(addr: A, server: ServerCtx) -> io::Result<()> {
-    let mut listen = tcp_listeners(addr);
-    while let Some((socket, _addr)) = listen.next().await {
+/// Remove the Unix socket at `path`, but never anything else
+fn remove_unix_socket(path: &str) {
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Removes the socket file when the listener is dropped
+struct UnixSocketFile(String);
+
+impl Drop for UnixSocketFile {
+    fn drop(&mut self) {
+        remove_unix_socket(&self.0);
+    }
+}
+
+/// A bound Unix socket, which is removed again when this is dropped
+struct UnixSocket {
+    listen: std::os::unix::net::UnixListener,
+    file: UnixSocketFile,
+}
+
+fn bind_unix(path: &str) -> Result<UnixSocket, Box<dyn Error>> {
+    // A socket left behind by a run that did not shut down cleanly would make bind fail
+    remove_unix_socket(path);
+    let listen = std::os::unix::net::UnixListener::bind(path)
+        .map_err(|e| format!("failed to bind unix:{path}: {e}"))?;
+    let file = UnixSocketFile(path.to_owned());
+    listen.set_nonblocking(true)?;
+    println!("Listening on unix:{path}");
+    Ok(UnixSocket { listen, file })
+}
+
+fn unix_listener(socket: UnixSocket) -> impl futures::Stream<Item = UnixStream> {
+    let listen = UnixListener::from_std(socket.listen).expect("Failed to register Unix socket");
+    futures::stream::unfold((listen, socket.file), async |(listen, file)| {
+        match listen.accept().await {
+            Ok((stream, _addr)) => Some((stream, (listen, file))),
+            Err(_) => None,
+        }
+    })
+}
+
+/// The listeners besides `listen_addr`, bound by [Listeners::bind]
+struct Listeners {
+    websocket: Vec<std::net::TcpListener>,
+    unix: Option<UnixSocket>,
+    metrics: Vec<std::net::TcpListener>,
+    viewer: Vec<std::net::TcpListener>,
+}
+
+impl Listeners {
+    fn bind(config: &Config) -> Result<Listeners, Box<dyn Error>> {
+        let tcp = |what, addr: &Option<String>| match addr {
+            Some(addr) => bind_tcp(what, addr),
+            None => Ok(Vec::new()),
+        };
+        Ok(Listeners {
+            websocket: tcp("WebSocket", &config.websocket_addr)?,
+            unix: config.unix_socket.as_deref().map(bind_unix).transpose()?,
+            metrics: tcp("metrics", &config.metrics_addr)?,
+            viewer: tcp("viewer", &config.viewer.addr)?,
+        })
+    }
+
+    /// Handles for another IO thread in reuseport mode, which accepts WebSocket clients as well. Only one thread can
+    /// listen on the Unix socket, and one is enough for the HTTP endpoints.
+    fn share(&self) -> io::Result<Listeners> {
+        Ok(Listeners {
+            websocket: self
+                .websocket
+                .iter()
+                .map(std::net::TcpListener::try_clone)
+                .collect::<io::Result<_>>()?,
+            unix: None,
+            metrics: Vec::new(),
+            viewer: Vec::new(),
+        })
+    }
+}
+
+/// Listeners for all client protocols, tagged with the client's address and the protocol they speak
+fn client_listeners(
+    config: &Config,
+    websocket: Vec<std::net::TcpListener>,
+    unix: Option<UnixSocket>,
+) -> impl futures::Stream<Item = (OwnedFd, IpAddr, Transport)> + use<> {
+    let reuse_port = config.accept_mode == AcceptMode::ReusePort;
+    let pixelflut = tcp_listeners(config.listen_addr.clone(), reuse_port)
+        .map(|(stream, addr)| (into_owned_fd(stream), addr.ip(), Transport::Pixelflut));
+    let websocket = accept_tcp(websocket)
+        .map(|(stream, addr)| (into_owned_fd(stream), addr.ip(), Transport::WebSocket));
+    // Unix socket clients count as local connections for the limits
+    let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let unix = unix.map(|socket| {
+        unix_listener(socket).map(move |stream| (into_owned_fd(stream), local, Transport::Unix))
+    });
+    futures::stream::select_all([
+        pixelflut.boxed_local(),
+        websocket.boxed_local(),
+        futures::stream::iter(unix).flatten().boxed_local(),
+    ])
+}
+
+/// Detach a socket from the runtime that accepted it, so that it can be served on any thread
+fn into_owned_fd(stream: impl IntoRawFd) -> OwnedFd {
+    unsafe { OwnedFd::from_raw_fd(stream.into_raw_fd()) }
+}
+
+/// How long an IO thread waits for its clients to finish after it stops accepting new ones
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The client tasks spawned on an IO thread, so that they can finish what they received on shutdown
+struct ClientTasks {
+    /// Every task holds a clone, so the channel closes once all of them (and this one) are gone
+    alive: async_channel::Sender<()>,
+    done: async_channel::Receiver<()>,
+    /// Counts the tasks that have finished, if set
+    finished: Option<&'static AtomicU64>,
+}
+
+impl ClientTasks {
+    fn new(finished: Option<&'static AtomicU64>) -> Self {
+        let (alive, done) = async_channel::bounded(1);
+        Self {
+            alive,
+            done,
+            finished,
+        }
+    }
+
+    fn spawn(&self, task: impl Future<Output = io::Result<()>> + 'static) {
+        let alive = self.alive.clone();
+        let finished = self.finished;
+        monoio::spawn(async move {
+            let _ = task.await;
+            if let Some(finished) = finished {
+                count(finished, 1);
+            }
+            drop(alive);
+        });
+    }
+
+    /// Wait for the remaining tasks, for at most [DRAIN_TIMEOUT] (clients may stay connected indefinitely)
+    async fn drain(self) {
+        drop(self.alive);
+        let _ = monoio::time::timeout(DRAIN_TIMEOUT, self.done.recv()).await;
+    }
+}
+
+/// Serve a client on the current thread
+fn spawn_client(
+    tasks: &ClientTasks,
+    stream: OwnedFd,
+    transport: Transport,
+    worker: &'static PixelflutThreadState,
+    guard: ConnectionGuard,
+) {
+    match transport {
+        Transport::Pixelflut => {
+            let stream = TcpStream::from_std(stream.into()).unwrap();
+            let client = PixelflutClient::new(stream, worker, guard);
+            tasks.spawn(tcp_pixelflut_handler(client));
+        }
+        Transport::WebSocket => {
+            let stream = TcpStream::from_std(stream.into()).unwrap();
+            let client = PixelflutClient::new(stream, worker, guard);
+            tasks.spawn(websocket_handler(client));
+        }
+        Transport::Unix => {
+            let stream = UnixStream::from_std(stream.into()).unwrap();
+            let client = PixelflutClient::new(stream, worker, guard);
+            tasks.spawn(tcp_pixelflut_handler(client));
+        }
+    }
+}
+
+async fn tcp_listener<S: futures::Stream<Item = (OwnedFd, IpAddr, Transport)>>(
+    listen: S,
+    mut server: ServerCtx,
+    state: &'static PixelflutGlobalState,
+) -> io::Result<()> {
+    let mut listen = std::pin::pin!(listen.take_until(state.shutdown.wait()));
+    while let Some((socket, ip, transport)) = listen.next().await {
         // println!("Socket!");
-        let socket = socket.into_raw_fd();
-        if !server.spawn(AcceptedClient { stream: socket }).await {
+        let Some(guard) = state.limits.connect(ip.to_canonical()) else {
+            // Too many connections from this address; dropping the socket closes it
+            continue;
+        };
+        if !server
+            .spawn(AcceptedClient {
+                stream: socket,
+                guard,
+                transport,
+            })
+            .await
+        {
             // println!("Die");
             break;
         }
@@ -85,109 +360,889 @@ async fn channel_spawner(
     worker: &'static PixelflutThreadState,
 ) {
     // println!("Receiver!");
+    let tasks = ClientTasks::new(Some(&worker.metrics.handoffs_closed));
     while let Ok(message) = channel.recv().await {
-        let stream =
-            TcpStream::from_std(unsafe { std::net::TcpStream::from_raw_fd(message.stream) })
-                .unwrap();
         // let current = thread::current();
         // let tid = current.name().unwrap_or("???");
         // println!("Spawning on {tid}");
-        monoio::spawn(tcp_pixelflut_handler(PixelflutClient::new(stream, worker)));
+        spawn_client(
+            &tasks,
+            message.stream,
+            message.transport,
+            worker,
+            message.guard,
+        );
+    }
+    // The channel closes on shutdown
+    tasks.drain().await;
+}
+
+/// Serve the HTTP metrics and viewer endpoints on their listeners, if any
+fn spawn_http_listeners(
+    metrics: Vec<std::net::TcpListener>,
+    viewer: Vec<std::net::TcpListener>,
+    game: &'static PixelflutGame,
+) {
+    if !metrics.is_empty() {
+        let listen = accept_tcp(metrics).take_until(game.shutdown().wait());
+        monoio::spawn(metrics_listener(listen, game));
+    }
+    if !viewer.is_empty() {
+        let listen = accept_tcp(viewer).take_until(game.shutdown().wait());
+        monoio::spawn(viewer_listener(listen, game));
     }
 }
 
 async fn main_thread(
     channel: async_channel::Receiver<AcceptedClient>,
-    worker: &'static PixelflutThreadState,
+    game: &'static PixelflutGame,
     config: Config,
     server: ServerCtx,
+    listeners: Listeners,
 ) {
+    let worker = game.for_worker(0);
+    let Listeners {
+        websocket,
+        unix,
+        metrics,
+        viewer,
+    } = listeners;
+    spawn_http_listeners(metrics, viewer, game);
+
     let (r1, _r2) = join!(
-        monoio::spawn(tcp_listener(config.listen_addr, server)),
+        monoio::spawn(tcp_listener(
+            client_listeners(&config, websocket, unix),
+            server,
+            worker.global_state
+        )),
         monoio::spawn(channel_spawner(channel, worker))
     );
     r1.unwrap();
 }
 
-fn setup_server(config: Config) -> (&'static PixelflutGame, Vec<thread::JoinHandle<()>>) {
-    assert!(config.num_io_threads >= 1);
+/// Run `main`, while also serving datagrams on this thread's handle of the UDP socket (if enabled)
+async fn with_udp(
+    udp: Option<std::net::UdpSocket>,
+    worker: &'static PixelflutThreadState,
+    main: impl Future<Output = ()>,
+) {
+    if let Some(udp) = udp {
+        let socket = UdpSocket::from_std(udp).expect("Failed to register UDP socket");
+        monoio::spawn(udp_listener(socket, worker));
+    }
+    main.await
+}
 
-    let game = PixelflutGame::new(&config);
+/// Accept connections on this thread's own SO_REUSEPORT listener, letting the kernel balance between threads
+async fn reuseport_thread(
+    game: &'static PixelflutGame,
+    thread_id: usize,
+    config: Config,
+    listeners: Listeners,
+) {
+    let worker = game.for_worker(thread_id);
+    let Listeners {
+        websocket,
+        unix,
+        metrics,
+        viewer,
+    } = listeners;
+    spawn_http_listeners(metrics, viewer, game);
 
-    let mut thread_spawners = Vec::new();
-    let mut thread_spawners_rx = Vec::new();
-    thread_spawners.reserve(config.num_io_threads);
-    thread_spawners_rx.reserve(config.num_io_threads);
-    for _thread_id in 0..config.num_io_threads {
-        let (tx, rx) = async_channel::bounded(128);
-        thread_spawners.push(tx);
-        thread_spawners_rx.push(rx);
+    let state = worker.global_state;
+    let listen = client_listeners(&config, websocket, unix);
+    let mut listen = std::pin::pin!(listen.take_until(state.shutdown.wait()));
+    let tasks = ClientTasks::new(None);
+    while let Some((stream, ip, transport)) = listen.next().await {
+        let Some(guard) = state.limits.connect(ip.to_canonical()) else {
+            continue;
+        };
+        spawn_client(&tasks, stream, transport, worker, guard);
     }
-    let server = ServerCtx {
-        thread_spawners: thread_spawners.into_boxed_slice(),
-    };
+    tasks.drain().await;
+}
+
+fn spawn_io_thread<F, Fut>(thread_id: usize, make_main: F) -> thread::JoinHandle<()>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()>,
+{
+    std::thread::Builder::new()
+        .name(format!("IO Worker {thread_id}"))
+        .spawn(move || {
+            let mut runtime = RuntimeBuilder::<FusionDriver>::new()
+                .with_entries(256)
+                .enable_timer()
+                .build()
+                .expect("Failed to initialize runtime");
+
+            runtime.block_on(make_main());
+        })
+        .expect("Spawn IO Thread")
+}
+
+/// The game and the threads to join on shutdown
+struct Server {
+    game: &'static PixelflutGame,
+    /// The IO threads, and everything else that ends on shutdown
+    threads: Vec<thread::JoinHandle<()>>,
+    /// Threads that persist what the IO threads did, so they end only once those did
+    after_io: Vec<thread::JoinHandle<()>>,
+}
+
+impl Server {
+    /// Wait for the server to stop, after shutdown was triggered
+    fn join(self) {
+        for join_h in self.threads {
+            join_h.join().unwrap();
+        }
+        self.game.io_stopped().trigger();
+        for join_h in self.after_io {
+            join_h.join().unwrap();
+        }
+    }
+}
+
+fn setup_server(config: Config) -> Result<Server, Box<dyn Error>> {
+    let game = PixelflutGame::new(&config)?;
+    let listeners = Listeners::bind(&config)?;
 
     let mut join = Vec::new();
-    // Spawn Main thread
-    let main_receiver = thread_spawners_rx[0].clone();
+    let mut after_io = Vec::new();
+    if let Some(journal_path) = &config.journal.path {
+        let writer = open_journal(journal_path, game)?;
+        after_io.push(
+            std::thread::Builder::new()
+                .name("Journal".to_owned())
+                .spawn(move || journal_loop(writer, game))
+                .expect("Spawn Journal Thread"),
+        );
+    }
     join.push(
         std::thread::Builder::new()
-            .name(format!("IO Worker 0"))
+            .name("Leaderboard".to_owned())
+            .spawn(move || leaderboard_loop(game))
+            .expect("Spawn Leaderboard Thread"),
+    );
+    let snapshot_config = config.snapshot.clone();
+    after_io.push(
+        std::thread::Builder::new()
+            .name("Snapshot".to_owned())
+            .spawn(move || snapshot_loop(game.image(), &snapshot_config, game.io_stopped()))
+            .expect("Spawn Snapshot Thread"),
+    );
+    if config.viewer.addr.is_some() {
+        let viewer_config = config.viewer.clone();
+        join.push(
+            std::thread::Builder::new()
+                .name("Viewer".to_owned())
+                .spawn(move || {
+                    viewer_loop(
+                        game.image(),
+                        game.viewers(),
+                        &viewer_config,
+                        game.shutdown(),
+                    )
+                })
+                .expect("Spawn Viewer Thread"),
+        );
+    }
+    if let Some(input) = &config.input {
+        let (reader, is_file) =
+            open_input(input).map_err(|e| format!("failed to open input '{input}': {e}"))?;
+        let worker = game.input_worker().unwrap();
+        let input_thread = std::thread::Builder::new()
+            .name("Input".to_owned())
             .spawn(move || {
                 let mut runtime = RuntimeBuilder::<FusionDriver>::new()
-                    .with_entries(256)
+                    .enable_timer()
                     .build()
                     .expect("Failed to initialize runtime");
-
-                runtime.block_on(main_thread(
-                    main_receiver,
-                    game.for_worker(0),
-                    config,
-                    server,
-                ));
+                let handler = input_handler(reader, io::stdout(), worker);
+                if let Err(e) = runtime.block_on(handler) {
+                    eprintln!("error: failed to process input: {e}");
+                }
             })
-            .expect("Spawn IO Thread"),
-    );
-    for (thread_id, spawner_channel_rx) in thread_spawners_rx.into_iter().enumerate().skip(1) {
-        join.push(
-            std::thread::Builder::new()
-                .name(format!("IO Worker {thread_id}"))
-                .spawn(move || {
-                    let mut runtime = RuntimeBuilder::<FusionDriver>::new()
-                        .with_entries(256)
-                        .build()
-                        .expect("Failed to initialize runtime");
+            .expect("Spawn Input Thread");
+        // Reading from a terminal or pipe may block until the process exits, but a file ends (at the latest on
+        // shutdown, which the input checks between reads)
+        if is_file {
+            join.push(input_thread);
+        }
+    }
+
+    let udp = match &config.udp_addr {
+        Some(udp_addr) => {
+            let socket = std::net::UdpSocket::bind(udp_addr)
+                .map_err(|e| format!("failed to bind UDP {udp_addr}: {e}"))?;
+            println!("Listening on udp:{}", socket.local_addr()?);
+            Some(socket)
+        }
+        None => None,
+    };
+    // Every IO thread receives from its own handle of the socket, and the kernel hands each datagram to one of them
+    let udp_handle = || udp.as_ref().map(std::net::UdpSocket::try_clone).transpose();
 
-                    runtime.block_on(channel_spawner(
-                        spawner_channel_rx,
+    match config.accept_mode {
+        AcceptMode::Handoff => {
+            let mut thread_spawners = Vec::new();
+            let mut thread_spawners_rx = Vec::new();
+            thread_spawners.reserve(config.num_io_threads);
+            thread_spawners_rx.reserve(config.num_io_threads);
+            for _thread_id in 0..config.num_io_threads {
+                let (tx, rx) = async_channel::bounded(128);
+                thread_spawners.push(tx);
+                thread_spawners_rx.push(rx);
+            }
+            let server = ServerCtx {
+                thread_spawners: thread_spawners.into_boxed_slice(),
+                workers: game.io_workers(),
+                placement: new_placement(config.placement, config.num_io_threads),
+            };
+
+            // Spawn Main thread
+            let main_receiver = thread_spawners_rx[0].clone();
+            let main_udp = udp_handle()?;
+            join.push(spawn_io_thread(0, move || {
+                with_udp(
+                    main_udp,
+                    game.for_worker(0),
+                    main_thread(main_receiver, game, config, server, listeners),
+                )
+            }));
+            for (thread_id, spawner_channel_rx) in
+                thread_spawners_rx.into_iter().enumerate().skip(1)
+            {
+                let udp = udp_handle()?;
+                join.push(spawn_io_thread(thread_id, move || {
+                    let worker = game.for_worker(thread_id);
+                    with_udp(udp, worker, channel_spawner(spawner_channel_rx, worker))
+                }));
+            }
+        }
+        AcceptMode::ReusePort => {
+            let shared = (1..config.num_io_threads)
+                .map(|_| listeners.share())
+                .collect::<io::Result<Vec<_>>>()?;
+            let listeners = std::iter::once(listeners).chain(shared);
+            for (thread_id, listeners) in listeners.enumerate() {
+                let config = config.clone();
+                let udp = udp_handle()?;
+                join.push(spawn_io_thread(thread_id, move || {
+                    with_udp(
+                        udp,
                         game.for_worker(thread_id),
-                    ));
-                })
-                .expect("Spawn IO Thread"),
-        );
+                        reuseport_thread(game, thread_id, config, listeners),
+                    )
+                }));
+            }
+        }
+    }
+
+    Ok(Server {
+        game,
+        threads: join,
+        after_io,
+    })
+}
+
+/// Run the frontend on the main thread until shutdown
+fn run_frontend(config: &Config, game: &'static PixelflutGame) -> Result<(), Box<dyn Error>> {
+    match config.frontend {
+        #[cfg(feature = "gstreamer")]
+        Frontend::Gstreamer => gstreamer_pipeline(config, game),
+        #[cfg(not(feature = "gstreamer"))]
+        Frontend::Gstreamer => unreachable!("rejected by Config::validate"),
+        #[cfg(feature = "winit")]
+        Frontend::Winit => winit_window_loop(config, game),
+        #[cfg(not(feature = "winit"))]
+        Frontend::Winit => unreachable!("rejected by Config::validate"),
+        // Only the server threads run
+        Frontend::None => {
+            game.shutdown().wait_blocking();
+            Ok(())
+        }
+    }
+}
+
+/// Exit on invalid arguments, or after printing the usage for `--help`
+fn parse_or_exit<T>(parsed: Result<T, ConfigError>) -> T {
+    match parsed {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            println!("{}", ConfigError::Help);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(2);
+        }
     }
+}
+
+/// Reconstruct the canvas from a journal, or re-render the session to video through the usual pipeline
+fn replay(config: &ReplayConfig) -> Result<(), Box<dyn Error>> {
+    let journal = config.journal.as_deref().unwrap();
+    if let Some(output) = &config.output {
+        let image = reconstruct(journal, config.at.map(Duration::from_secs_f64))?;
+        write_snapshot(&image, output)?;
+        return Ok(());
+    }
+    replay_video(config, journal)
+}
 
-    (game, join)
+#[cfg(not(feature = "gstreamer"))]
+fn replay_video(_config: &ReplayConfig, _journal: &str) -> Result<(), Box<dyn Error>> {
+    Err("rendering a replay to video requires building with the gstreamer feature".into())
+}
+
+/// Re-render the session through the gstreamer pipeline, paced by the journal's timestamps
+#[cfg(feature = "gstreamer")]
+fn replay_video(config: &ReplayConfig, journal: &str) -> Result<(), Box<dyn Error>> {
+    let (image_width, image_height) = JournalReader::dimensions(journal)?;
+    let pipeline_config = Config {
+        image_width,
+        image_height,
+        gst_window: config.window,
+        record_to_file: config.video.clone(),
+        ..Default::default()
+    };
+    let game = PixelflutGame::new(&pipeline_config)?;
+    let signals = install_signal_handler(game.shutdown());
+
+    let replay_thread = std::thread::Builder::new()
+        .name("Replay".to_owned())
+        .spawn({
+            let journal = journal.to_owned();
+            let speed = config.speed;
+            move || {
+                let replayed = replay_loop(&journal, game.image(), speed, game.shutdown());
+                // Ends the video
+                game.shutdown().trigger();
+                replayed
+            }
+        })
+        .expect("Spawn Replay Thread");
+    let rendered = gstreamer_pipeline(&pipeline_config, game);
+    // Stops the replay if the pipeline failed to start
+    game.shutdown().trigger();
+
+    signals.close();
+    replay_thread.join().unwrap()?;
+    rendered
 }
 
 fn main() {
-    // FIXME: read from TOML
-    let config = Config {
-        num_io_threads: 4,
-        image_width: 1280,
-        image_height: 720,
-        listen_addr: "127.0.0.1:4000".to_owned(),
-        gst_window: true,
-        // record_to_file: None,
-        record_to_file: Some("meow.mkv".to_string()),
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if(|arg| arg == "replay").is_some() {
+        let config = parse_or_exit(ReplayConfig::from_args(args));
+        if let Err(e) = replay(&config) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config = parse_or_exit(Config::from_args(args));
+
+    let server = match setup_server(config.clone()) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
     };
 
-    let (game, join) = setup_server(config.clone());
+    let game = server.game;
+    let signals = install_signal_handler(game.shutdown());
+
+    let frontend = run_frontend(&config, game);
+    if frontend.is_err() {
+        game.shutdown().trigger();
+    }
+
+    server.join();
+    signals.close();
+    if let Err(e) = frontend {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::setup_server;
+    use crate::core::{
+        config::{AcceptMode, CanvasFit, Config, LimitsConfig, OverLimit, ViewerConfig},
+        image::{PixelflutImage, RGBAPixel},
+        journal::{reconstruct, JournalEntry, JournalReader, JournalRecord},
+        snapshot::load_canvas,
+    };
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream, UdpSocket},
+        os::unix::net::UnixStream,
+        thread,
+        time::Duration,
+    };
+    use test::Bencher;
+
+    /// Connect `clients` times from several threads at once, waiting for each SIZE response
+    fn connection_storm(addr: &str, clients: usize) {
+        const THREADS: usize = 8;
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..clients / THREADS {
+                        let mut stream = TcpStream::connect(addr).unwrap();
+                        stream.write_all(b"SIZE\n").unwrap();
+                        let mut response = String::new();
+                        BufReader::new(stream).read_line(&mut response).unwrap();
+                        assert_eq!(response, "SIZE 64 32\r\n");
+                    }
+                });
+            }
+        });
+    }
+
+    fn free_addr() -> String {
+        let listen = TcpListener::bind("127.0.0.1:0").unwrap();
+        listen.local_addr().unwrap().to_string()
+    }
+
+    fn scanout(image: &PixelflutImage) -> Vec<u8> {
+        let mut data = vec![0; image.scanout_size()];
+        image.scanout(&mut data);
+        data
+    }
+
+    fn test_config(accept_mode: AcceptMode) -> Config {
+        Config {
+            num_io_threads: 4,
+            image_width: 64,
+            image_height: 32,
+            // Every thread binds the same port in reuseport mode, so we cannot just use port 0
+            listen_addr: free_addr(),
+            accept_mode,
+            ..Default::default()
+        }
+    }
+
+    fn with_server(config: Config, f: impl FnOnce(&Config)) {
+        let server = setup_server(config.clone()).unwrap();
+
+        // The IO threads bind asynchronously
+        while TcpStream::connect(&config.listen_addr).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        f(&config);
 
-    // winit_window_loop(&config, game);
-    gstreamer_pipeline(&config, game);
+        server.game.shutdown().trigger();
+        server.join();
+    }
+
+    #[test]
+    fn test_accept_modes() {
+        for mode in [AcceptMode::Handoff, AcceptMode::ReusePort] {
+            with_server(test_config(mode), |config| {
+                connection_storm(&config.listen_addr, 64)
+            });
+        }
+    }
+
+    #[test]
+    fn test_busy_addrs() {
+        let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy = Some(busy.local_addr().unwrap().to_string());
+        let configs = [
+            Config {
+                metrics_addr: busy.clone(),
+                ..test_config(AcceptMode::Handoff)
+            },
+            Config {
+                websocket_addr: busy.clone(),
+                ..test_config(AcceptMode::ReusePort)
+            },
+            Config {
+                viewer: ViewerConfig {
+                    addr: busy.clone(),
+                    ..Default::default()
+                },
+                ..test_config(AcceptMode::Handoff)
+            },
+            Config {
+                unix_socket: Some("/nonexistent/pixelflut.sock".to_owned()),
+                ..test_config(AcceptMode::Handoff)
+            },
+        ];
+        // Rejected on startup, before any thread is spawned
+        for config in configs {
+            assert!(setup_server(config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_drain_on_shutdown() {
+        let snapshot =
+            std::env::temp_dir().join(format!("pixelflut-drain-{}.png", std::process::id()));
+        let journal = std::env::temp_dir().join(format!("pixelflut-drain-{}", std::process::id()));
+        let journal = journal.to_str().unwrap();
+        for mode in [AcceptMode::Handoff, AcceptMode::ReusePort] {
+            let mut config = test_config(mode);
+            config.snapshot.path = Some(snapshot.to_str().unwrap().to_owned());
+            config.journal.path = Some(journal.to_owned());
+            let server = setup_server(config.clone()).unwrap();
+            let game = server.game;
+            let mut stream = loop {
+                if let Ok(stream) = TcpStream::connect(&config.listen_addr) {
+                    break stream;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+            // Make sure the client is being served before shutting down
+            stream.write_all(b"SIZE\n").unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            reader.read_line(&mut String::new()).unwrap();
+
+            // Commands still in flight at shutdown are executed, down to the last one
+            let mut commands = Vec::new();
+            for _ in 0..100 {
+                for y in 0..32 {
+                    for x in 0..64 {
+                        commands.extend_from_slice(format!("PX {x} {y} ff0000\n").as_bytes());
+                    }
+                }
+            }
+            commands.extend_from_slice(b"PX 3 4 00ff00\n");
+            stream.write_all(&commands).unwrap();
+            game.shutdown().trigger();
+            server.join();
+            assert_eq!(
+                game.image().get_pixel(3, 4).into_rgba(),
+                RGBAPixel::new_rgb(0, 0xFF, 0).into_rgba()
+            );
+
+            // The final snapshot is taken and the journal is written out after the drain
+            let saved = load_canvas(snapshot.to_str().unwrap(), 64, 32, CanvasFit::Crop).unwrap();
+            assert_eq!(scanout(&saved), scanout(game.image()));
+            let replayed = reconstruct(journal, None).unwrap();
+            assert_eq!(scanout(&replayed), scanout(game.image()));
+            std::fs::remove_file(journal).unwrap();
+        }
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+
+    /// Read a single unfragmented, unmasked frame
+    fn read_ws_frame(stream: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        assert!(header[1] < 126, "unexpectedly long frame");
+        let mut payload = vec![0; header[1] as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    #[test]
+    fn test_websocket() {
+        let config = Config {
+            websocket_addr: Some(free_addr()),
+            ..test_config(AcceptMode::Handoff)
+        };
+        with_server(config, |config| {
+            let mut stream = TcpStream::connect(config.websocket_addr.as_ref().unwrap()).unwrap();
+            stream
+                .write_all(
+                    b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                reader.read_until(b'\n', &mut response).unwrap();
+            }
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 101 "));
+            assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+            // Two commands in one masked text frame, the last one without a newline
+            let mask = [1, 2, 3, 4];
+            let payload = b"PX 1 2 ff0000\nPX 1 2";
+            let mut frame = vec![0x81, 0x80 | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            stream.write_all(&frame).unwrap();
+            assert_eq!(
+                read_ws_frame(&mut reader),
+                (0x81, b"PX 1 2 ff0000\r\n".to_vec())
+            );
+
+            // Unmasked binary frame holding a PB command, a text frame, then a close frame
+            let mut frame = vec![0x82, 10, b'P', b'B', 1, 0, 2, 0, 0, 0xff, 0, 0xff];
+            frame.extend_from_slice(b"\x81\x06PX 1 2\x88\x02\x03\xe8");
+            stream.write_all(&frame).unwrap();
+            assert_eq!(
+                read_ws_frame(&mut reader),
+                (0x81, b"PX 1 2 00ff00\r\n".to_vec())
+            );
+            assert_eq!(read_ws_frame(&mut reader), (0x88, vec![0x03, 0xe8]));
+        });
+    }
+
+    fn http_get(addr: &str, path: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        BufReader::new(stream)
+    }
+
+    /// Read lines up to and including the next empty one
+    fn read_head(reader: &mut impl BufRead) -> String {
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert_ne!(reader.read_line(&mut head).unwrap(), 0, "unexpected EOF");
+        }
+        head
+    }
+
+    #[test]
+    fn test_viewer() {
+        let mut config = test_config(AcceptMode::Handoff);
+        config.viewer = ViewerConfig {
+            addr: Some(free_addr()),
+            fps: 100,
+            max_viewers: 1,
+            ..Default::default()
+        };
+        with_server(config, |config| {
+            let addr = config.viewer.addr.as_ref().unwrap();
+            let mut page = String::new();
+            http_get(addr, "/").read_to_string(&mut page).unwrap();
+            assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(page.contains("<img src=\"/stream\""));
+
+            let mut viewer = http_get(addr, "/stream");
+            assert!(read_head(&mut viewer).contains("multipart/x-mixed-replace"));
+            let part = read_head(&mut viewer);
+            assert!(part.contains("Content-Type: image/jpeg\r\n"), "{part}");
+            let len: usize = part
+                .split("Content-Length: ")
+                .nth(1)
+                .and_then(|rest| rest.split("\r\n").next())
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut jpeg = vec![0; len];
+            viewer.read_exact(&mut jpeg).unwrap();
+            let frame = image::load_from_memory(&jpeg).unwrap();
+            assert_eq!((frame.width(), frame.height()), (64, 32));
+
+            let mut rejected = String::new();
+            http_get(addr, "/stream")
+                .read_to_string(&mut rejected)
+                .unwrap();
+            assert!(rejected.starts_with("HTTP/1.1 503 "));
+        });
+    }
+
+    #[test]
+    fn test_journal() {
+        let path =
+            std::env::temp_dir().join(format!("pixelflut-journal-e2e-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let mut config = test_config(AcceptMode::Handoff);
+        config.journal.path = Some(path.clone());
+        with_server(config, |config| {
+            let mut stream = TcpStream::connect(&config.listen_addr).unwrap();
+            stream
+                .write_all(b"OFFSET 10 10\nPX 1 2 ff0000\nRECT 0 0 100 100 00ff0080\nPX 0 0\n")
+                .unwrap();
+            // Wait for the response to know that all commands have been executed
+            let mut response = String::new();
+            BufReader::new(stream).read_line(&mut response).unwrap();
+        });
+
+        let entries: Vec<JournalEntry> = JournalReader::open(&path)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let [session, set, rect] = entries[..] else {
+            panic!("unexpected journal {entries:?}");
+        };
+        assert_eq!(
+            session.record,
+            JournalRecord::Session {
+                width: 64,
+                height: 32
+            }
+        );
+        assert_eq!(
+            set.record,
+            JournalRecord::SetPixel {
+                x: 11,
+                y: 12,
+                pixel: RGBAPixel::new_rgb(0xff, 0, 0)
+            }
+        );
+        assert_eq!(
+            rect.record,
+            JournalRecord::Rect {
+                x: 10,
+                y: 10,
+                w: 54,
+                h: 22,
+                pixel: RGBAPixel::new_rgba(0, 0xff, 0, 0x80)
+            }
+        );
+        assert_ne!(set.connection, 0);
+        assert_eq!(set.connection, rect.connection);
+        assert!(session.time_us <= set.time_us && set.time_us <= rect.time_us);
+
+        let image = reconstruct(&path, None).unwrap();
+        assert_eq!(image.get_pixel(11, 12).channels(), [0x7f, 0x80, 0, 0xff]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_leaderboard() {
+        with_server(test_config(AcceptMode::Handoff), |config| {
+            let mut stream = TcpStream::connect(&config.listen_addr).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream
+                .write_all(b"PX 0 0 ff0000\nRECT 0 0 4 4 00ff00\n")
+                .unwrap();
+
+            // The tallies of the IO threads are merged periodically
+            let mut response = String::new();
+            loop {
+                stream.write_all(b"LEADERBOARD\n").unwrap();
+                response.clear();
+                reader.read_line(&mut response).unwrap();
+                if response != "LEADERBOARD\r\n" {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(response, "LEADERBOARD 127.0.0.1=17\r\n");
+        });
+
+        let mut config = test_config(AcceptMode::Handoff);
+        config.leaderboard.command = false;
+        with_server(config, |config| {
+            let mut stream = TcpStream::connect(&config.listen_addr).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"LEADERBOARD\n").unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            assert_eq!(response, "error: LEADERBOARD is disabled\r\n");
+        });
+    }
+
+    #[test]
+    fn test_local_transports() {
+        let dir = std::env::temp_dir();
+        let socket = dir.join(format!("pixelflut-unix-{}", std::process::id()));
+        let input = dir.join(format!("pixelflut-input-{}", std::process::id()));
+        std::fs::write(&input, "PX 3 4 0000ff\n").unwrap();
+
+        // A socket left behind by a server that did not shut down cleanly is replaced
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        for mode in [AcceptMode::Handoff, AcceptMode::ReusePort] {
+            let mut config = test_config(mode);
+            config.unix_socket = Some(socket.to_str().unwrap().to_owned());
+            config.input = Some(input.to_str().unwrap().to_owned());
+            with_server(config, |_| {
+                let mut stream = UnixStream::connect(&socket).unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                stream.write_all(b"PX 1 2 ff0000\nPX 1 2\n").unwrap();
+                let mut response = String::new();
+                reader.read_line(&mut response).unwrap();
+                assert_eq!(response, "PX 1 2 ff0000\r\n");
+
+                // The input is executed on its own thread
+                loop {
+                    stream.write_all(b"PX 3 4\n").unwrap();
+                    response.clear();
+                    reader.read_line(&mut response).unwrap();
+                    if response == "PX 3 4 0000ff\r\n" {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            assert!(!socket.exists(), "socket not removed on shutdown");
+        }
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn test_udp() {
+        let udp_addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = test_config(AcceptMode::ReusePort);
+        config.udp_addr = Some(udp_addr.to_string());
+        config.limits = LimitsConfig {
+            pixels_per_second: Some(1),
+            // Every thread can take a batch of credit, whichever one receives the datagram
+            pixel_burst: Some(1000),
+            over_limit: OverLimit::Error,
+            ..Default::default()
+        };
+        with_server(config, |_| {
+            let request = |source: &UdpSocket, datagram: &[u8]| {
+                source.send_to(datagram, udp_addr).unwrap();
+                let mut response = [0; 1024];
+                let n = source.recv(&mut response).unwrap();
+                String::from_utf8(response[..n].to_vec()).unwrap()
+            };
+            let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+            a.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            // The end of the datagram ends the last command; every response is its own datagram
+            assert_eq!(
+                request(&a, b"OFFSET 1 1\nPX 0 1 ff0000\nPX 0 1"),
+                "PX 0 1 ff0000\r\n"
+            );
+            let mut datagram = b"PB\x01\x00\x02\x00\x00\xff\x00\xff".to_vec();
+            // Padded, since the replies must not be larger than the request
+            datagram.extend_from_slice(b"PX 1 2\r\nSIZE      ");
+            assert_eq!(request(&a, &datagram), "PX 1 2 00ff00\r\n");
+            let mut response = [0; 64];
+            let n = a.recv(&mut response).unwrap();
+            assert_eq!(&response[..n], b"SIZE 64 32\r\n");
+
+            // Nothing that would amplify spoofed requests is answered, so the next reply is to the padded PX
+            for datagram in [&b"SIZE"[..], b"PX 1 2"] {
+                a.send_to(datagram, udp_addr).unwrap();
+            }
+            let padding = [b' '; 64];
+            for datagram in [&b"HELP"[..], b"LEADERBOARD", b"bogus"] {
+                a.send_to(&[datagram, &padding[..]].concat(), udp_addr)
+                    .unwrap();
+            }
+            assert_eq!(request(&a, b"PX 1 2         "), "PX 1 2 00ff00\r\n");
+
+            // The source does not have the budget for the whole canvas (and gets no error), but others still have
+            // theirs
+            a.send_to(b"RECT 0 0 64 32 ffffff", udp_addr).unwrap();
+            assert_eq!(request(&a, b"PX 5 5         "), "PX 5 5 000000\r\n");
+            let b = UdpSocket::bind("127.0.0.2:0").unwrap();
+            b.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(request(&b, b"PX 0 0 0000ff\nPX 0 0"), "PX 0 0 0000ff\r\n");
+        });
+    }
+
+    #[bench]
+    fn bench_connection_storm_handoff(b: &mut Bencher) {
+        with_server(test_config(AcceptMode::Handoff), |config| {
+            b.iter(|| connection_storm(&config.listen_addr, 256))
+        });
+    }
 
-    for join_h in join {
-        join_h.join().unwrap();
+    #[bench]
+    fn bench_connection_storm_reuseport(b: &mut Bencher) {
+        with_server(test_config(AcceptMode::ReusePort), |config| {
+            b.iter(|| connection_storm(&config.listen_addr, 256))
+        });
     }
 }
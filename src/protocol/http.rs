@@ -0,0 +1,92 @@
+use std::io;
+
+use monoio::io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt};
+
+/// Requests larger than this are rejected; we only need the request line and a few headers
+const MAX_REQUEST: usize = 8192;
+
+/// The head of an HTTP request. The body, if any, is ignored.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Option<Request> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split_ascii_whitespace();
+        let method = request_line.next()?.to_owned();
+        let path = request_line.next()?.to_owned();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+            .collect();
+        Some(Request {
+            method,
+            path,
+            headers,
+        })
+    }
+
+    /// Look up a header by its case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read the head of an HTTP request. Returns None if the connection is closed before it is complete or the
+/// request is malformed.
+pub async fn read_request(stream: &mut impl AsyncReadRent) -> io::Result<Option<Request>> {
+    let mut request = Vec::with_capacity(1024);
+    loop {
+        let chunk = Vec::with_capacity(1024);
+        let (res, chunk) = stream.read(chunk).await;
+        if res? == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&chunk);
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            request.truncate(end);
+            break;
+        }
+        if request.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+    }
+    Ok(Request::parse(&String::from_utf8_lossy(&request)))
+}
+
+/// Write a complete response and ask the client to close the connection
+pub async fn write_response(
+    stream: &mut impl AsyncWriteRent,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.into_bytes()).await.0?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Request;
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            Request::parse("GET /ws HTTP/1.1\r\nHost: localhost\r\nupgrade:  websocket").unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/ws");
+        assert_eq!(request.header("Upgrade"), Some("websocket"));
+        assert_eq!(request.header("Connection"), None);
+        assert!(Request::parse("").is_none());
+    }
+}
@@ -0,0 +1,35 @@
+use std::{io, net::SocketAddr};
+
+use futures::{Stream, StreamExt};
+use monoio::net::TcpStream;
+
+use super::http::{read_request, write_response};
+use crate::core::{game::PixelflutGame, metrics::render_metrics};
+
+async fn serve_metrics(mut stream: TcpStream, game: &'static PixelflutGame) -> io::Result<()> {
+    let Some(request) = read_request(&mut stream).await? else {
+        return Ok(());
+    };
+
+    let (status, content_type, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics" | "/") => ("200 OK", "text/plain; version=0.0.4", render_metrics(game)),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_owned(),
+        ),
+    };
+    write_response(&mut stream, status, content_type, &body).await
+}
+
+/// Serve Prometheus metrics to every connection from `listen`
+pub async fn metrics_listener<S: Stream<Item = (TcpStream, SocketAddr)>>(
+    listen: S,
+    game: &'static PixelflutGame,
+) {
+    let mut listen = std::pin::pin!(listen);
+    while let Some((stream, _addr)) = listen.next().await {
+        monoio::spawn(serve_metrics(stream, game));
+    }
+}
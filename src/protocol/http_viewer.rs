@@ -0,0 +1,84 @@
+use std::{io, net::SocketAddr};
+
+use futures::{Stream, StreamExt};
+use monoio::{io::AsyncWriteRentExt, net::TcpStream};
+
+use super::http::{read_request, write_response};
+use crate::core::{game::PixelflutGame, viewer::ViewerFeed};
+
+const VIEWER_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Pixelflut</title>
+<style>
+body { margin: 0; height: 100vh; display: flex; align-items: center; justify-content: center; background: #111; }
+img { max-width: 100%; max-height: 100%; image-rendering: pixelated; }
+</style>
+</head>
+<body><img src="/stream" alt="Pixelflut canvas"></body>
+</html>
+"#;
+
+const BOUNDARY: &str = "pixelflut-frame";
+
+/// Push frames to the viewer as a multipart/x-mixed-replace (MJPEG) stream, until it disconnects
+async fn stream_frames(stream: &mut TcpStream, feed: &ViewerFeed) -> io::Result<()> {
+    let Some(frames) = feed.subscribe() else {
+        let body = "too many viewers, try again later\n";
+        return write_response(stream, "503 Service Unavailable", "text/plain", body).await;
+    };
+
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(header.into_bytes()).await.0?;
+    // The receiver fails once the feed is closed on shutdown
+    while let Ok(frame) = frames.recv().await {
+        let part_header = format!(
+            "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            frame.len()
+        );
+        stream.write_all(part_header.into_bytes()).await.0?;
+        stream.write_all(frame).await.0?;
+        stream.write_all(&b"\r\n"[..]).await.0?;
+    }
+    Ok(())
+}
+
+async fn serve_viewer(mut stream: TcpStream, game: &'static PixelflutGame) -> io::Result<()> {
+    let Some(request) = read_request(&mut stream).await? else {
+        return Ok(());
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => {
+            write_response(
+                &mut stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                VIEWER_PAGE,
+            )
+            .await
+        }
+        ("GET", "/stream") => stream_frames(&mut stream, game.viewers()).await,
+        ("GET", _) => {
+            write_response(&mut stream, "404 Not Found", "text/plain", "not found\n").await
+        }
+        _ => {
+            let body = "method not allowed\n";
+            write_response(&mut stream, "405 Method Not Allowed", "text/plain", body).await
+        }
+    }
+}
+
+/// Serve the live canvas viewer to every connection from `listen`
+pub async fn viewer_listener<S: Stream<Item = (TcpStream, SocketAddr)>>(
+    listen: S,
+    game: &'static PixelflutGame,
+) {
+    let mut listen = std::pin::pin!(listen);
+    while let Some((stream, _addr)) = listen.next().await {
+        monoio::spawn(serve_viewer(stream, game));
+    }
+}
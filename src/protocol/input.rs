@@ -0,0 +1,170 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr},
+    os::fd::AsFd,
+};
+
+use monoio::{
+    buf::{IoBuf, IoBufMut, IoVecBuf, IoVecBufMut, RawBuf},
+    io::{AsyncReadRent, AsyncWriteRent},
+    BufResult,
+};
+
+use super::tcp_pixelflut::{tcp_pixelflut_handler, PixelflutClient};
+use crate::core::{shutdown::Shutdown, state::PixelflutThreadState};
+
+/// Adapts a blocking reader and writer to monoio's IO traits. Every operation blocks the whole runtime, so this
+/// must only be used on a thread that serves nothing else.
+pub struct BlockingStream<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> BlockingStream<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+
+    fn read_into<T: IoBufMut>(&mut self, buf: &mut T) -> io::Result<usize> {
+        let len = buf.bytes_total();
+        // Zero the spare capacity first, since we must not hand out uninitialized memory as a slice
+        let slice = unsafe {
+            buf.write_ptr().write_bytes(0, len);
+            std::slice::from_raw_parts_mut(buf.write_ptr(), len)
+        };
+        let n = self.reader.read(slice)?;
+        unsafe { buf.set_init(n) };
+        Ok(n)
+    }
+
+    fn write_from<T: IoBuf>(&mut self, buf: &T) -> io::Result<usize> {
+        let slice = unsafe { std::slice::from_raw_parts(buf.read_ptr(), buf.bytes_init()) };
+        let n = self.writer.write(slice)?;
+        // Responses should show up right away, even if the writer is buffered
+        self.writer.flush()?;
+        Ok(n)
+    }
+}
+
+impl<R: Read, W: Write> AsyncReadRent for BlockingStream<R, W> {
+    fn read<T: IoBufMut>(&mut self, mut buf: T) -> impl Future<Output = BufResult<usize, T>> {
+        let res = self.read_into(&mut buf);
+        async move { (res, buf) }
+    }
+
+    fn readv<T: IoVecBufMut>(&mut self, mut buf: T) -> impl Future<Output = BufResult<usize, T>> {
+        // Like the implementation for &[u8], only fill the first buffer
+        let res = match unsafe { RawBuf::new_from_iovec_mut(&mut buf) } {
+            Some(mut raw_buf) => self.read_into(&mut raw_buf),
+            None => Ok(0),
+        };
+        if let Ok(n) = res {
+            unsafe { buf.set_init(n) };
+        }
+        async move { (res, buf) }
+    }
+}
+
+impl<R: Read, W: Write> AsyncWriteRent for BlockingStream<R, W> {
+    fn write<T: IoBuf>(&mut self, buf: T) -> impl Future<Output = BufResult<usize, T>> {
+        let res = self.write_from(&buf);
+        async move { (res, buf) }
+    }
+
+    fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> impl Future<Output = BufResult<usize, T>> {
+        let res = match unsafe { RawBuf::new_from_iovec(&buf_vec) } {
+            Some(raw_buf) => self.write_from(&raw_buf),
+            None => Ok(0),
+        };
+        async move { (res, buf_vec) }
+    }
+
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> {
+        let res = self.writer.flush();
+        async move { res }
+    }
+
+    fn shutdown(&mut self) -> impl Future<Output = io::Result<()>> {
+        self.flush()
+    }
+}
+
+/// Open the input file, where '-' means stdin. Also returns whether it is a regular file (including stdin
+/// redirected from one), i.e. whether reading it is bound to end.
+pub fn open_input(path: &str) -> io::Result<(Box<dyn Read + Send>, bool)> {
+    if path == "-" {
+        let stdin = io::stdin();
+        let is_file = File::from(stdin.as_fd().try_clone_to_owned()?)
+            .metadata()?
+            .is_file();
+        return Ok((Box::new(stdin), is_file));
+    }
+    let file = File::open(path)?;
+    let is_file = file.metadata()?.is_file();
+    Ok((Box::new(file), is_file))
+}
+
+/// Ends the input on shutdown, so that a long dump does not hold up the server
+struct UntilShutdown<R> {
+    reader: R,
+    shutdown: &'static Shutdown,
+}
+
+impl<R: Read> Read for UntilShutdown<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.shutdown.is_triggered() {
+            return Ok(0);
+        }
+        self.reader.read(buf)
+    }
+}
+
+/// Execute all commands from `reader` as if they were sent by a local client, writing responses to `writer`.
+pub async fn input_handler<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    worker: &'static PixelflutThreadState,
+) -> io::Result<()> {
+    // Input counts as a local connection for the limits, just like Unix socket clients
+    let Some(guard) = worker
+        .global_state
+        .limits
+        .connect(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    else {
+        return Err(io::Error::other("too many local connections"));
+    };
+    let reader = UntilShutdown {
+        reader,
+        shutdown: &worker.global_state.shutdown,
+    };
+    let client = PixelflutClient::new(BlockingStream::new(reader, writer), worker, guard);
+    tcp_pixelflut_handler(client).await
+}
+
+#[cfg(test)]
+mod tests {
+    use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+
+    use super::BlockingStream;
+
+    #[test]
+    fn test_blocking_stream() {
+        futures::executor::block_on(blocking_stream());
+    }
+
+    async fn blocking_stream() {
+        let mut stream = BlockingStream::new(&b"PX 1 2\nSIZE\n"[..], Vec::new());
+        let (res, buf) = stream.read(Vec::with_capacity(4)).await;
+        assert_eq!(res.unwrap(), 4);
+        assert_eq!(buf, b"PX 1");
+        let (res, buf) = stream.read(Vec::with_capacity(64)).await;
+        assert_eq!(res.unwrap(), 8);
+        assert_eq!(buf, b" 2\nSIZE\n");
+        assert_eq!(stream.read(Vec::with_capacity(64)).await.0.unwrap(), 0);
+
+        stream.write_all(&b"SIZE 64 32\r\n"[..]).await.0.unwrap();
+        assert_eq!(stream.writer, b"SIZE 64 32\r\n");
+    }
+}
@@ -1 +1,7 @@
-pub mod tcp_pixelflut;
\ No newline at end of file
+pub mod tcp_pixelflut;
+pub mod http;
+pub mod http_metrics;
+pub mod http_viewer;
+pub mod input;
+pub mod websocket;
+pub mod udp_pixelflut;
\ No newline at end of file
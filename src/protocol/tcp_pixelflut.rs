@@ -1,35 +1,115 @@
 use core::str;
-use std::io;
+use std::{fmt::Write, io, mem};
 
 use arrayvec::ArrayVec;
 use monoio::{
     buf::IoBuf,
-    io::{AsyncReadRent, AsyncWriteRent},
-    net::TcpStream,
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt},
 };
 
+use super::websocket::{encode_frame, OPCODE_TEXT};
 use crate::core::{
+    config::OverLimit,
     image::{Coord, PixelflutImage, RGBAPixel},
+    journal::JournalRecord,
+    limits::{ConnectionGuard, PixelGrant},
+    metrics::{count, CommandKind},
     state::PixelflutThreadState,
 };
 
-pub struct PixelflutClient {
-    stream: TcpStream,
-    worker: &'static PixelflutThreadState,
+/// How responses are written to the stream
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Raw,
+    /// Every response is a WebSocket text message
+    WebSocket,
+    /// Every response is its own UDP datagram
+    Datagram,
+}
+
+/// A client connected over any byte stream, such as a TCP or Unix socket
+pub struct PixelflutClient<S> {
+    pub(super) stream: S,
+    pub(super) worker: &'static PixelflutThreadState,
+    guard: ConnectionGuard,
+    pub(super) framing: Framing,
+    /// Identifies the client in the journal
+    connection_id: u32,
+    /// Pixels set since they were last added to the worker's tally for the leaderboard
+    unreported_pixels: u64,
 
     base_x: Coord,
     base_y: Coord,
 }
 
-impl PixelflutClient {
-    pub fn new(stream: TcpStream, worker: &'static PixelflutThreadState) -> PixelflutClient {
+impl<S> PixelflutClient<S> {
+    pub fn new(
+        stream: S,
+        worker: &'static PixelflutThreadState,
+        guard: ConnectionGuard,
+    ) -> PixelflutClient<S> {
+        count(&worker.metrics.connections_accepted, 1);
         Self {
             stream,
             worker,
+            guard,
+            framing: Framing::Raw,
+            connection_id: worker.global_state.journal.next_connection_id(),
+            unreported_pixels: 0,
             base_x: 0,
             base_y: 0,
         }
     }
+
+    /// A client that executes datagrams from any source, see [PixelflutClient::begin_datagram]. It is not counted as
+    /// a connection.
+    pub(super) fn new_datagram(
+        stream: S,
+        worker: &'static PixelflutThreadState,
+        guard: ConnectionGuard,
+    ) -> PixelflutClient<S> {
+        Self {
+            stream,
+            worker,
+            guard,
+            framing: Framing::Datagram,
+            connection_id: worker.global_state.journal.next_connection_id(),
+            unreported_pixels: 0,
+            base_x: 0,
+            base_y: 0,
+        }
+    }
+
+    /// Prepare for executing a datagram. OFFSET does not carry over, since consecutive datagrams may come from
+    /// different sources; `guard` replaces the rate limit of the previous source if given. A new source also gets
+    /// its own connection id in the journal.
+    pub(super) fn begin_datagram(&mut self, guard: Option<ConnectionGuard>) {
+        if let Some(guard) = guard {
+            self.report_pixels();
+            self.guard = guard;
+            self.connection_id = self.worker.global_state.journal.next_connection_id();
+        }
+        self.base_x = 0;
+        self.base_y = 0;
+    }
+
+    /// Add the pixels set so far to the worker's tally for the leaderboard. This takes a lock, so we only do it
+    /// once per read rather than for every command.
+    pub(super) fn report_pixels(&mut self) {
+        if self.unreported_pixels > 0 {
+            let pixels = mem::take(&mut self.unreported_pixels);
+            self.worker.count_pixels_by_ip(self.guard.ip(), pixels);
+        }
+    }
+}
+
+impl<S> Drop for PixelflutClient<S> {
+    fn drop(&mut self) {
+        self.report_pixels();
+        if self.framing != Framing::Datagram {
+            count(&self.worker.metrics.connections_closed, 1);
+        }
+    }
 }
 
 fn parse_hex1(hx_char: u8) -> Option<u8> {
@@ -104,10 +184,36 @@ pub enum PixelflutCommand {
         y: Coord,
         pixel: RGBAPixel,
     },
+    GetPixel {
+        x: Coord,
+        y: Coord,
+    },
+    Rect {
+        x: Coord,
+        y: Coord,
+        w: Coord,
+        h: Coord,
+        pixel: RGBAPixel,
+    },
     Offset {
         x: Coord,
         y: Coord,
     },
+    Leaderboard,
+}
+
+impl PixelflutCommand {
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            PixelflutCommand::Help => CommandKind::Help,
+            PixelflutCommand::Size => CommandKind::Size,
+            PixelflutCommand::SetPixel { .. } => CommandKind::SetPixel,
+            PixelflutCommand::GetPixel { .. } => CommandKind::GetPixel,
+            PixelflutCommand::Rect { .. } => CommandKind::Rect,
+            PixelflutCommand::Offset { .. } => CommandKind::Offset,
+            PixelflutCommand::Leaderboard => CommandKind::Leaderboard,
+        }
+    }
 }
 
 fn parse_pixelflut_request(line: &[u8]) -> Option<PixelflutCommand> {
@@ -117,17 +223,32 @@ fn parse_pixelflut_request(line: &[u8]) -> Option<PixelflutCommand> {
     if subcommand == b"PX" {
         let w_x = split.next()?;
         let w_y = split.next()?;
-        let w_rgba = split.next()?;
-
-        let r_rgba = parse_rgba(w_rgba)?;
         let r_x = atoi_coord(w_x)?;
         let r_y = atoi_coord(w_y)?;
 
+        let Some(w_rgba) = split.next() else {
+            return Some(PixelflutCommand::GetPixel { x: r_x, y: r_y });
+        };
+        let r_rgba = parse_rgba(w_rgba)?;
+
         Some(PixelflutCommand::SetPixel {
             x: r_x,
             y: r_y,
             pixel: r_rgba,
         })
+    } else if subcommand == b"RECT" {
+        let r_x = atoi_coord(split.next()?)?;
+        let r_y = atoi_coord(split.next()?)?;
+        let r_w = atoi_coord(split.next()?)?;
+        let r_h = atoi_coord(split.next()?)?;
+        let r_rgba = parse_rgba(split.next()?)?;
+        Some(PixelflutCommand::Rect {
+            x: r_x,
+            y: r_y,
+            w: r_w,
+            h: r_h,
+            pixel: r_rgba,
+        })
     } else if subcommand == b"SIZE" {
         Some(PixelflutCommand::Size)
     } else if subcommand == b"HELP" {
@@ -138,34 +259,206 @@ fn parse_pixelflut_request(line: &[u8]) -> Option<PixelflutCommand> {
         let r_x = atoi_coord(w_x)?;
         let r_y = atoi_coord(w_y)?;
         Some(PixelflutCommand::Offset { x: r_x, y: r_y })
+    } else if subcommand == b"LEADERBOARD" {
+        Some(PixelflutCommand::Leaderboard)
     } else {
         None
     }
 }
 
+/// `PB<x: u16 LE><y: u16 LE><r><g><b><a>`; see [parse_binary_pixel]
+const BINARY_PIXEL_LEN: usize = 10;
+
+fn parse_binary_pixel(frame: &[u8; BINARY_PIXEL_LEN]) -> PixelflutCommand {
+    let x = u16::from_le_bytes([frame[2], frame[3]]);
+    let y = u16::from_le_bytes([frame[4], frame[5]]);
+    PixelflutCommand::SetPixel {
+        x: x as Coord,
+        y: y as Coord,
+        pixel: RGBAPixel::new_rgba(frame[6], frame[7], frame[8], frame[9]),
+    }
+}
+
+/// A complete unit of client input
+pub enum Frame<'a> {
+    /// A text command, without the line terminator
+    Line(&'a [u8]),
+    /// An already decoded binary command
+    Command(PixelflutCommand),
+}
+
+/// Split the next frame off the front of `buf`, returning it together with the number of bytes it occupies.
+/// Returns None if `buf` does not contain a complete frame yet.
+fn next_frame(buf: &[u8]) -> Option<(Frame<'_>, usize)> {
+    if buf.starts_with(b"PB") {
+        let frame = buf.first_chunk::<BINARY_PIXEL_LEN>()?;
+        return Some((Frame::Command(parse_binary_pixel(frame)), BINARY_PIXEL_LEN));
+    }
+
+    let newline = buf.iter().position(|&c| c == b'\n')?;
+    let mut line = &buf[..newline];
+    // Remove carriage return
+    if let Some((b'\r', rest)) = line.split_last() {
+        line = rest;
+    }
+    Some((Frame::Line(line), newline + 1))
+}
+
+pub enum Decoded<'a> {
+    Frame(Frame<'a>),
+    /// A line exceeded the maximum length; it is discarded up to the next newline
+    LineTooLong,
+    /// All input has been consumed
+    NeedMore,
+}
+
+/// The longest line accepted, not counting the line terminator
+const MAX_LINE_LEN: usize = 128;
+
+/// Splits a byte stream into frames, carrying incomplete frames over between reads.
+pub struct PixelflutDecoder {
+    /// Room for a line of [MAX_LINE_LEN] and its "\r\n"
+    pending: ArrayVec<u8, { MAX_LINE_LEN + 2 }>,
+    pending_consumed: bool,
+    discarding: bool,
+}
+
+impl Default for PixelflutDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PixelflutDecoder {
+    pub fn new() -> Self {
+        Self {
+            pending: ArrayVec::new(),
+            pending_consumed: false,
+            discarding: false,
+        }
+    }
+
+    /// Decode the next frame from `data`, advancing it past the consumed bytes.
+    pub fn next<'s, 'd: 's>(&'s mut self, data: &mut &'d [u8]) -> Decoded<'s> {
+        // The previous frame may have borrowed from pending, so we can only clear it now
+        if self.pending_consumed {
+            self.pending.clear();
+            self.pending_consumed = false;
+        }
+
+        if self.discarding {
+            let Some(newline) = data.iter().position(|&c| c == b'\n') else {
+                *data = &[];
+                return Decoded::NeedMore;
+            };
+            *data = &data[newline + 1..];
+            self.discarding = false;
+        }
+
+        if self.pending.is_empty() {
+            let input: &'d [u8] = data;
+            return match next_frame(input) {
+                Some((Frame::Line(line), _)) if line.len() > MAX_LINE_LEN => {
+                    *data = &input[line.len()..];
+                    self.discarding = true;
+                    Decoded::LineTooLong
+                }
+                Some((frame, consumed)) => {
+                    *data = &input[consumed..];
+                    Decoded::Frame(frame)
+                }
+                None if input.is_empty() => Decoded::NeedMore,
+                // Without its "\n", a line that still fits can be followed by at most a "\r"
+                None if input.len() > MAX_LINE_LEN + 1 => {
+                    *data = &[];
+                    self.discarding = true;
+                    Decoded::LineTooLong
+                }
+                None => {
+                    self.pending.try_extend_from_slice(input).unwrap();
+                    *data = &[];
+                    Decoded::NeedMore
+                }
+            };
+        }
+
+        // Complete the frame left over from the previous read
+        let old_len = self.pending.len();
+        let take = self.pending.remaining_capacity().min(data.len());
+        self.pending.try_extend_from_slice(&data[..take]).unwrap();
+        // NOTE: we cannot match on the frame directly, since returning it would keep pending borrowed in the other arms
+        let complete = next_frame(&self.pending).map(|(frame, consumed)| {
+            let too_long = matches!(frame, Frame::Line(line) if line.len() > MAX_LINE_LEN);
+            (too_long, consumed)
+        });
+        match complete {
+            Some((too_long, consumed)) => {
+                *data = &data[consumed - old_len..];
+                self.pending_consumed = true;
+                if too_long {
+                    Decoded::LineTooLong
+                } else {
+                    Decoded::Frame(next_frame(&self.pending).unwrap().0)
+                }
+            }
+            None if self.pending.is_full() => {
+                *data = &data[take..];
+                self.pending.clear();
+                self.discarding = true;
+                Decoded::LineTooLong
+            }
+            None => {
+                *data = &[];
+                Decoded::NeedMore
+            }
+        }
+    }
+}
+
 const HELP_TEXT: &str =
     "Pixelflut Server by Nikita Bloshchanevich (https://github.com/nbfalcon/pixelflut_monoio)
 
 Accepted Commands:
-- OFFSET X Y: configure the offset for all subsequent PX commands (X and Y are added to X Y from PX)
-- PX X Y <hex-color code: RGB | RRGGBB | RRGGBBAA>: set pixel at X, Y to color
+- OFFSET X Y: configure the offset for all subsequent PX, PB and RECT commands (X and Y are added to their X Y)
+- PX X Y <hex-color code: RGB | RRGGBB | RRGGBBAA>: set pixel at X, Y to color (AA blends the color over the current one)
+- PX X Y: return the color of the pixel at X, Y (response is a line PX X Y RRGGBB)
+- RECT X Y W H <hex-color code>: fill the W x H rectangle at X, Y with color (clipped at the edge of the board)
 - SIZE: return the SIZE of the board (response is a line SIZE <width> <height>)
+- LEADERBOARD: return the addresses that set the most pixels (response is a line LEADERBOARD <address>=<pixels> ...,
+  unless disabled by the server)
+- PB<X: u16 LE><Y: u16 LE><R><G><B><A>: binary PX (exactly 10 bytes, not followed by a newline)
 
-All numbers are in decimal (except color codes).
+All numbers are in decimal (except color codes and PB).
 
 Examples:
 PX 10 10 FFF
 PX 10 11 ffaa00
-PX 10 12 ffaa00ff\r\n";
+PX 10 12 ffaa00ff
+RECT 10 10 20 5 00ff0080\r\n";
 // USE \r\n to terminate the message. This is a bit hacky, but this way, the client can always just assume reading until \r\n for respones.
 
-impl PixelflutClient {
-    async fn respond<T: IoBuf>(&mut self, s: T) -> io::Result<()> {
-        self.stream.write(s).await.0?;
+impl<S: AsyncWriteRent> PixelflutClient<S> {
+    async fn respond<T: IoBuf + AsRef<[u8]>>(&mut self, s: T) -> io::Result<()> {
+        match self.framing {
+            Framing::Raw | Framing::Datagram => self.stream.write(s).await.0?,
+            Framing::WebSocket => {
+                let frame = encode_frame(OPCODE_TEXT, s.as_ref());
+                self.stream.write_all(frame).await.0?
+            }
+        };
         Ok(())
     }
 
-    async fn respond_error<T: IoBuf>(&mut self, s: T) -> io::Result<()> {
+    /// Whether replies may be larger than the request. Datagrams can have spoofed source addresses, so replying
+    /// with more than we received would make us an amplifier for reflection attacks.
+    fn may_amplify(&self) -> bool {
+        self.framing != Framing::Datagram
+    }
+
+    async fn respond_error<T: IoBuf + AsRef<[u8]>>(&mut self, s: T) -> io::Result<()> {
+        if !self.may_amplify() {
+            return Ok(());
+        }
         self.respond(s).await
     }
 
@@ -180,8 +473,30 @@ impl PixelflutClient {
         None
     }
 
+    /// Take `n` pixels from the client's budget. Returns false if they should be discarded.
+    async fn take_pixels(&mut self, n: u64) -> io::Result<bool> {
+        loop {
+            let PixelGrant::Exhausted(wait) = self.guard.take_pixels(n) else {
+                return Ok(true);
+            };
+            match self.worker.global_state.limits.over_limit() {
+                // Waiting would hold up the datagrams of all other sources on this thread
+                OverLimit::Delay if self.framing == Framing::Datagram => return Ok(false),
+                OverLimit::Delay => monoio::time::sleep(wait).await,
+                OverLimit::Drop => return Ok(false),
+                OverLimit::Error => {
+                    self.respond_error("error: pixel rate limit exceeded\r\n")
+                        .await?;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
     pub async fn execute_command(&mut self, cmd: PixelflutCommand) -> Result<(), io::Error> {
+        self.worker.metrics.count_command(cmd.kind());
         Ok(match cmd {
+            PixelflutCommand::Help if !self.may_amplify() => {}
             PixelflutCommand::Help => {
                 self.respond(HELP_TEXT)
                     .await?;
@@ -189,10 +504,8 @@ impl PixelflutClient {
             PixelflutCommand::Size => {
                 let w = self.worker.global_config.width;
                 let h = self.worker.global_config.height;
-                self.stream
-                    .write(format!("SIZE {w} {h}\r\n").into_bytes())
-                    .await
-                    .0?;
+                self.respond(format!("SIZE {w} {h}\r\n").into_bytes())
+                    .await?;
             }
             PixelflutCommand::SetPixel { x, y, pixel } => {
                 let image = &self.worker.global_state.image;
@@ -200,13 +513,79 @@ impl PixelflutClient {
                     self.respond_error("error: pixel out of bounds").await?;
                     return Ok(());
                 };
+                if !self.take_pixels(1).await? {
+                    return Ok(());
+                }
+
+                image.draw_pixel(abs_x, abs_y, pixel);
+                count(&self.worker.metrics.pixels_set, 1);
+                self.unreported_pixels += 1;
+                self.worker.journal(
+                    self.connection_id,
+                    JournalRecord::SetPixel {
+                        x: abs_x,
+                        y: abs_y,
+                        pixel,
+                    },
+                );
+            }
+            PixelflutCommand::GetPixel { x, y } => {
+                let image = &self.worker.global_state.image;
+                let Some((abs_x, abs_y)) = self.boundscheck(x, y, image) else {
+                    self.respond_error("error: pixel out of bounds").await?;
+                    return Ok(());
+                };
 
-                // FIXME: blend in CAS here
-                image.set_pixel(abs_x, abs_y, pixel);
+                let [r, g, b, _a] = image.get_pixel(abs_x, abs_y).channels();
+                self.respond(format!("PX {x} {y} {r:02x}{g:02x}{b:02x}\r\n").into_bytes())
+                    .await?;
+            }
+            PixelflutCommand::Rect { x, y, w, h, pixel } => {
+                let image = &self.worker.global_state.image;
+                let (Some(abs_x), Some(abs_y)) =
+                    (x.checked_add(self.base_x), y.checked_add(self.base_y))
+                else {
+                    return Ok(());
+                };
+                let Some((abs_x, abs_y, w, h)) = image.clip_rect(abs_x, abs_y, w, h) else {
+                    return Ok(());
+                };
+                if !self.take_pixels(w as u64 * h as u64).await? {
+                    return Ok(());
+                }
+
+                image.fill_rect(abs_x, abs_y, w, h, pixel);
+                count(&self.worker.metrics.pixels_set, w as u64 * h as u64);
+                self.unreported_pixels += w as u64 * h as u64;
+                self.worker.journal(
+                    self.connection_id,
+                    JournalRecord::Rect {
+                        x: abs_x,
+                        y: abs_y,
+                        w,
+                        h,
+                        pixel,
+                    },
+                );
             }
             PixelflutCommand::Offset { x, y } => {
                 self.base_x = x;
                 self.base_y = y;
+            }
+            PixelflutCommand::Leaderboard if !self.may_amplify() => {}
+            PixelflutCommand::Leaderboard
+                if !self.worker.global_state.leaderboard.command_enabled() =>
+            {
+                self.respond_error("error: LEADERBOARD is disabled\r\n")
+                    .await?;
+            }
+            PixelflutCommand::Leaderboard => {
+                let mut response = "LEADERBOARD".to_owned();
+                for (ip, pixels) in self.worker.global_state.leaderboard.top_shown() {
+                    write!(response, " {ip}={pixels}").unwrap();
+                }
+                response += "\r\n";
+                self.respond(response.into_bytes()).await?;
             } // FIXME: better error messages
               // 1. Handle the case of unknown command better
               // 2. "Expect no more arguments"
@@ -217,6 +596,7 @@ impl PixelflutClient {
     pub async fn dispatch_line(&mut self, line: &[u8]) -> io::Result<()> {
         let Some(cmd) = parse_pixelflut_request(line) else {
             let line_s = str::from_utf8(line).unwrap_or("<invalid UTF-8>");
+            count(&self.worker.metrics.parse_errors, 1);
             let errmsg = format!("error: syntax error or unknown command '{line_s}'\r\n");
             eprintln!("{errmsg}");
             self.respond_error(errmsg.into_bytes()).await?;
@@ -227,54 +607,57 @@ impl PixelflutClient {
 
         Ok(())
     }
+
+    pub async fn dispatch_frame(&mut self, frame: Frame<'_>) -> io::Result<()> {
+        match frame {
+            Frame::Line(line) => self.dispatch_line(line).await,
+            Frame::Command(cmd) => self.execute_command(cmd).await,
+        }
+    }
+
+    /// Dispatch all commands in a self-contained message, whose end also terminates the last line
+    pub async fn dispatch_message(&mut self, mut message: &[u8]) -> io::Result<()> {
+        while !message.is_empty() {
+            let Some((frame, consumed)) = next_frame(message) else {
+                return self
+                    .dispatch_line(message.strip_suffix(b"\r").unwrap_or(message))
+                    .await;
+            };
+            self.dispatch_frame(frame).await?;
+            message = &message[consumed..];
+        }
+        Ok(())
+    }
 }
 
-pub async fn tcp_pixelflut_handler(mut client: PixelflutClient) -> io::Result<()> {
-    let mut linebuf = ArrayVec::<u8, 128>::new();
+pub async fn tcp_pixelflut_handler<S: AsyncReadRent + AsyncWriteRent>(
+    mut client: PixelflutClient<S>,
+) -> io::Result<()> {
+    let mut decoder = PixelflutDecoder::new();
     let mut rxbuf: Vec<u8> = Vec::with_capacity(4096);
     loop {
         let res;
         (res, rxbuf) = client.stream.read(rxbuf).await;
-        if res? == 0 {
+        let n = res?;
+        if n == 0 {
             break; // Handle EOF: https://github.com/bytedance/monoio/blob/master/examples/echo.rs
         }
-
-        let mut split = rxbuf
-            .split(|&c| c == b'\n')
-            .map(|mut l| {
-                // Remove carriage return
-                if let Some((b'\r', rest)) = l.split_last() {
-                    l = rest;
+        count(&client.worker.metrics.bytes_received, n as u64);
+
+        let mut data = rxbuf.as_slice();
+        loop {
+            match decoder.next(&mut data) {
+                Decoded::Frame(frame) => client.dispatch_frame(frame).await?,
+                Decoded::LineTooLong => {
+                    count(&client.worker.metrics.parse_errors, 1);
+                    client
+                        .respond_error("error: line too long (discarding)\r\n")
+                        .await?
                 }
-                l
-            })
-            .peekable();
-
-        let first_segment = split.next().unwrap();
-        if linebuf.try_extend_from_slice(first_segment).is_ok() {
-            client.dispatch_line(linebuf.as_slice()).await?;
-            linebuf.clear();
-        } else {
-            client
-                .respond_error("error: line too long (discarding)")
-                .await?;
-        }
-        while let Some(line) = split.next() {
-            if line.len() > linebuf.capacity() {
-                client
-                    .respond_error("error: line too long (discarding)")
-                    .await?;
-                continue;
-            }
-
-            if split.peek().is_some() {
-                client.dispatch_line(line).await?;
-            } else {
-                // Last element
-                linebuf.clear();
-                linebuf.try_extend_from_slice(line).unwrap();
+                Decoded::NeedMore => break,
             }
         }
+        client.report_pixels();
     }
 
     Ok(())
@@ -282,11 +665,166 @@ pub async fn tcp_pixelflut_handler(mut client: PixelflutClient) -> io::Result<()
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_pixelflut_request, parse_rgba};
+    use super::{
+        parse_pixelflut_request, parse_rgba, Decoded, Frame, PixelflutCommand, PixelflutDecoder,
+        MAX_LINE_LEN,
+    };
+    use crate::core::image::{PixelflutImage, RGBAPixel};
+    use test::Bencher;
+
+    /// Decode all of `input`, fed in chunks of `chunk` bytes
+    fn decode_all(input: &[u8], chunk: usize) -> Vec<Option<Vec<u8>>> {
+        decode_reads(input.chunks(chunk))
+    }
+
+    /// Decode the data of consecutive reads
+    fn decode_reads<'a>(reads: impl IntoIterator<Item = &'a [u8]>) -> Vec<Option<Vec<u8>>> {
+        let mut decoder = PixelflutDecoder::new();
+        let mut frames = Vec::new();
+        for mut data in reads {
+            loop {
+                match decoder.next(&mut data) {
+                    Decoded::Frame(Frame::Line(line)) => frames.push(Some(line.to_vec())),
+                    Decoded::Frame(Frame::Command(PixelflutCommand::SetPixel { x, y, pixel })) => {
+                        let [r, g, b, a] = pixel.channels();
+                        frames.push(Some(format!("PB {x} {y} {r} {g} {b} {a}").into_bytes()))
+                    }
+                    Decoded::Frame(Frame::Command(_)) => unreachable!(),
+                    Decoded::LineTooLong => frames.push(None),
+                    Decoded::NeedMore => break,
+                }
+            }
+        }
+        frames
+    }
 
     #[test]
     fn test_parsers() {
         parse_rgba(b"ffff00").unwrap();
         parse_pixelflut_request(b"PX 24 50 ffff00").unwrap();
+        assert!(matches!(
+            parse_pixelflut_request(b"LEADERBOARD"),
+            Some(PixelflutCommand::Leaderboard)
+        ));
+    }
+
+    #[test]
+    fn test_parse_get_pixel() {
+        assert!(matches!(
+            parse_pixelflut_request(b"PX 24 50"),
+            Some(PixelflutCommand::GetPixel { x: 24, y: 50 })
+        ));
+        assert!(parse_pixelflut_request(b"PX 24").is_none());
+        assert!(parse_pixelflut_request(b"PX 24 50 fffff").is_none());
+    }
+
+    #[test]
+    fn test_parse_rect() {
+        assert!(matches!(
+            parse_pixelflut_request(b"RECT 1 2 30 40 ff000080"),
+            Some(PixelflutCommand::Rect {
+                x: 1,
+                y: 2,
+                w: 30,
+                h: 40,
+                ..
+            })
+        ));
+        assert!(parse_pixelflut_request(b"RECT 1 2 30 ff0000").is_none());
+    }
+
+    #[test]
+    fn test_decoder() {
+        let mut input = b"SIZE\r\nPB".to_vec();
+        input.extend_from_slice(&[0x01, 0x02, 0x03, 0x00, 1, 2, 3, 4]);
+        input.extend_from_slice(b"PX 1 2 fff\n");
+        input.extend_from_slice(&[b'A'; 200]);
+        input.extend_from_slice(b"\nHELP\n");
+
+        let expected: Vec<Option<Vec<u8>>> = vec![
+            Some(b"SIZE".to_vec()),
+            Some(b"PB 513 3 1 2 3 4".to_vec()),
+            Some(b"PX 1 2 fff".to_vec()),
+            None,
+            Some(b"HELP".to_vec()),
+        ];
+        // Every frame boundary must survive being split across reads
+        for chunk in [1, 3, 7, 64, 4096] {
+            assert_eq!(decode_all(&input, chunk), expected, "chunk size {chunk}");
+        }
+    }
+
+    #[test]
+    fn test_decoder_max_line_len() {
+        let longest = vec![b'A'; MAX_LINE_LEN];
+        let too_long = vec![b'A'; MAX_LINE_LEN + 1];
+        for (line, terminator) in [
+            (&longest, &b"\n"[..]),
+            (&longest, b"\r\n"),
+            (&too_long, b"\n"),
+            (&too_long, b"\r\n"),
+        ] {
+            let mut input = line.clone();
+            input.extend_from_slice(terminator);
+            input.extend_from_slice(b"SIZE\n");
+            let first = (line.len() <= MAX_LINE_LEN).then(|| line.clone());
+            let expected = vec![first, Some(b"SIZE".to_vec())];
+            // The limit must not depend on where the line is split between reads
+            for at in 0..=input.len() {
+                let (a, b) = input.split_at(at);
+                assert_eq!(
+                    decode_reads([a, b]),
+                    expected,
+                    "{} + {terminator:?} split at {at}",
+                    line.len()
+                );
+            }
+        }
+    }
+
+    fn bench_decode(b: &mut Bencher, input: &[u8]) {
+        let image = PixelflutImage::new_with(256, 256);
+        b.bytes = input.len() as u64;
+        b.iter(|| {
+            let mut decoder = PixelflutDecoder::new();
+            for mut data in input.chunks(4096) {
+                loop {
+                    let cmd = match decoder.next(&mut data) {
+                        Decoded::Frame(Frame::Line(line)) => parse_pixelflut_request(line),
+                        Decoded::Frame(Frame::Command(cmd)) => Some(cmd),
+                        Decoded::LineTooLong => None,
+                        Decoded::NeedMore => break,
+                    };
+                    if let Some(PixelflutCommand::SetPixel { x, y, pixel }) = cmd {
+                        image.set_pixel(x, y, pixel);
+                    }
+                }
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_text_pixels(b: &mut Bencher) {
+        let mut input = Vec::new();
+        for y in 0..256 {
+            for x in 0..256 {
+                input.extend_from_slice(format!("PX {x} {y} ff{:02x}00\n", x ^ y).as_bytes());
+            }
+        }
+        bench_decode(b, &input);
+    }
+
+    #[bench]
+    fn bench_binary_pixels(b: &mut Bencher) {
+        let mut input = Vec::new();
+        for y in 0..256u16 {
+            for x in 0..256u16 {
+                input.extend_from_slice(b"PB");
+                input.extend_from_slice(&x.to_le_bytes());
+                input.extend_from_slice(&y.to_le_bytes());
+                input.extend_from_slice(&RGBAPixel::new_rgb(0xFF, (x ^ y) as u8, 0).channels());
+            }
+        }
+        bench_decode(b, &input);
     }
 }
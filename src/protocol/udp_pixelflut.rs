@@ -0,0 +1,125 @@
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use monoio::{
+    buf::{IoBuf, IoVecBuf, RawBuf},
+    io::AsyncWriteRent,
+    net::udp::UdpSocket,
+    BufResult,
+};
+
+use super::tcp_pixelflut::PixelflutClient;
+use crate::core::{metrics::count, state::PixelflutThreadState};
+
+/// The largest possible UDP payload
+const MAX_DATAGRAM: usize = 65535;
+
+/// Sends every response as a datagram back to the source of the datagram being executed
+pub struct DatagramReply {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    /// Bytes that may still be sent in reply to the current datagram. Responses that do not fit are dropped, so that
+    /// we never send more than we received (source addresses can be spoofed).
+    budget: usize,
+}
+
+impl DatagramReply {
+    /// Take `len` bytes from the budget, if there are enough left
+    fn take_budget(&mut self, len: usize) -> bool {
+        let fits = len <= self.budget;
+        if fits {
+            self.budget -= len;
+        }
+        fits
+    }
+}
+
+impl AsyncWriteRent for DatagramReply {
+    fn write<T: IoBuf>(&mut self, buf: T) -> impl Future<Output = BufResult<usize, T>> {
+        let len = buf.bytes_init();
+        let send = self.take_budget(len);
+        async move {
+            if !send {
+                return (Ok(len), buf);
+            }
+            self.socket.send_to(buf, self.peer).await
+        }
+    }
+
+    fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> impl Future<Output = BufResult<usize, T>> {
+        // Only the first buffer, like a short write
+        let raw_buf = unsafe { RawBuf::new_from_iovec(&buf_vec) };
+        let send = raw_buf
+            .as_ref()
+            .is_some_and(|raw_buf| self.take_budget(raw_buf.bytes_init()));
+        async move {
+            let res = match raw_buf {
+                Some(raw_buf) if send => self.socket.send_to(raw_buf, self.peer).await.0,
+                Some(raw_buf) => Ok(raw_buf.bytes_init()),
+                None => Ok(0),
+            };
+            (res, buf_vec)
+        }
+    }
+
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> {
+        std::future::ready(Ok(()))
+    }
+
+    fn shutdown(&mut self) -> impl Future<Output = io::Result<()>> {
+        std::future::ready(Ok(()))
+    }
+}
+
+/// Execute the datagrams received on this thread. Every datagram holds one or more commands, just like the TCP
+/// protocol, except that the end of the datagram also ends the last command. OFFSET only applies to the rest of its
+/// datagram, and pixels are rate limited by source address.
+///
+/// Replies never add up to more than the datagram they answer, so requests for longer ones (e.g. a lone `SIZE`) must
+/// be padded with whitespace. Errors, HELP and LEADERBOARD are never answered.
+async fn serve_datagrams(
+    socket: UdpSocket,
+    worker: &'static PixelflutThreadState,
+) -> io::Result<()> {
+    let limits = &worker.global_state.limits;
+    let peer = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let reply = DatagramReply {
+        socket,
+        peer,
+        budget: 0,
+    };
+    // All datagrams of a thread share one client, which keeps the per-datagram overhead low
+    let mut client = PixelflutClient::new_datagram(reply, worker, limits.source(peer.ip()));
+
+    let mut rxbuf: Vec<u8> = Vec::with_capacity(MAX_DATAGRAM);
+    loop {
+        let res;
+        (res, rxbuf) = client.stream.socket.recv_from(rxbuf).await;
+        let (n, peer) = res?;
+        count(&client.worker.metrics.bytes_received, n as u64);
+
+        // Only look up the budget of the source if it changed, since that takes a global lock
+        let ip = peer.ip().to_canonical();
+        let guard = (ip != client.stream.peer.ip().to_canonical()).then(|| limits.source(ip));
+        client.stream.peer = peer;
+        client.stream.budget = n;
+        client.begin_datagram(guard);
+        // Failing to reply (e.g. to an unreachable source) must not stop the listener
+        let _ = client.dispatch_message(&rxbuf).await;
+        client.report_pixels();
+    }
+}
+
+/// Serve Pixelflut over UDP on `socket` until shutdown
+pub async fn udp_listener(socket: UdpSocket, worker: &'static PixelflutThreadState) {
+    let serve = std::pin::pin!(serve_datagrams(socket, worker));
+    let shutdown = std::pin::pin!(worker.global_state.shutdown.wait());
+    if let futures::future::Either::Left((Err(e), _)) =
+        futures::future::select(serve, shutdown).await
+    {
+        eprintln!("error: UDP listener failed: {e}");
+    }
+}
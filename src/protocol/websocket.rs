@@ -0,0 +1,235 @@
+use std::io;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use monoio::io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt};
+use sha1::{Digest, Sha1};
+
+use super::{
+    http::{read_request, write_response},
+    tcp_pixelflut::{Framing, PixelflutClient},
+};
+use crate::core::metrics::count;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Larger messages close the connection; a message is executed only once it is complete
+const MAX_MESSAGE: usize = 1 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: [u8; 4],
+    len: u64,
+}
+
+/// Parse the header at the start of `buf`, returning it together with its length.
+/// Returns None if `buf` does not contain the complete header yet.
+fn parse_frame_header(buf: &[u8]) -> Option<(FrameHeader, usize)> {
+    let [b0, b1, ..] = *buf else {
+        return None;
+    };
+    let (len, mut pos) = match b1 & 0x7F {
+        126 => (u16::from_be_bytes(*buf[2..].first_chunk()?) as u64, 4),
+        127 => (u64::from_be_bytes(*buf[2..].first_chunk()?), 10),
+        len => (len as u64, 2),
+    };
+    // Clients must mask their frames; an all-zero mask leaves the payload as is
+    let mut mask = [0; 4];
+    if b1 & 0x80 != 0 {
+        mask = *buf[pos..].first_chunk()?;
+        pos += 4;
+    }
+    let header = FrameHeader {
+        fin: b0 & 0x80 != 0,
+        opcode: b0 & 0x0F,
+        mask,
+        len,
+    };
+    Some((header, pos))
+}
+
+/// Encode a single unmasked, unfragmented frame, as sent by servers
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..126 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn unmask(payload: &[u8], mask: [u8; 4]) -> impl Iterator<Item = u8> + '_ {
+    payload
+        .iter()
+        .enumerate()
+        .map(move |(i, b)| b ^ mask[i % 4])
+}
+
+/// Perform the opening handshake. Returns false if the request was not a WebSocket upgrade.
+async fn handshake<S: AsyncReadRent + AsyncWriteRent>(
+    client: &mut PixelflutClient<S>,
+) -> io::Result<bool> {
+    let stream = &mut client.stream;
+    // Clients must wait for our response before sending frames, so nothing can follow the request
+    let Some(request) = read_request(stream).await? else {
+        return Ok(false);
+    };
+    let is_upgrade = request
+        .header("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let (true, true, Some(key)) = (
+        request.method == "GET",
+        is_upgrade,
+        request.header("Sec-WebSocket-Key"),
+    ) else {
+        let body = "this endpoint only speaks Pixelflut over WebSocket\n";
+        write_response(stream, "400 Bad Request", "text/plain", body).await?;
+        return Ok(false);
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.into_bytes()).await.0?;
+    client.framing = Framing::WebSocket;
+    Ok(true)
+}
+
+async fn close<S: AsyncWriteRent>(client: &mut PixelflutClient<S>, status: u16) -> io::Result<()> {
+    let frame = encode_frame(OPCODE_CLOSE, &status.to_be_bytes());
+    client.stream.write_all(frame).await.0?;
+    Ok(())
+}
+
+/// Serve Pixelflut over WebSocket. Every text or binary message holds one or more commands, just like the TCP
+/// protocol, except that the end of the message also ends the last command. Responses are sent as text messages.
+pub async fn websocket_handler<S: AsyncReadRent + AsyncWriteRent>(
+    mut client: PixelflutClient<S>,
+) -> io::Result<()> {
+    if !handshake(&mut client).await? {
+        return Ok(());
+    }
+
+    let mut buf = Vec::with_capacity(4096);
+    let mut message = Vec::new();
+    let mut rxbuf: Vec<u8> = Vec::with_capacity(4096);
+    loop {
+        let res;
+        (res, rxbuf) = client.stream.read(rxbuf).await;
+        let n = res?;
+        if n == 0 {
+            break;
+        }
+        count(&client.worker.metrics.bytes_received, n as u64);
+        buf.extend_from_slice(&rxbuf);
+
+        let mut data = buf.as_slice();
+        while let Some((header, header_len)) = parse_frame_header(data) {
+            if header.len > (MAX_MESSAGE - message.len()) as u64 {
+                return close(&mut client, CLOSE_TOO_BIG).await;
+            }
+            let frame_len = header_len + header.len as usize;
+            let Some(payload) = data.get(header_len..frame_len) else {
+                break;
+            };
+            data = &data[frame_len..];
+
+            match header.opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    message.extend(unmask(payload, header.mask));
+                    if header.fin {
+                        client.dispatch_message(&message).await?;
+                        message.clear();
+                    }
+                }
+                OPCODE_PING => {
+                    let pong = encode_frame(
+                        OPCODE_PONG,
+                        &unmask(payload, header.mask).collect::<Vec<_>>(),
+                    );
+                    client.stream.write_all(pong).await.0?;
+                }
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let status: Vec<u8> = unmask(payload, header.mask).take(2).collect();
+                    client
+                        .stream
+                        .write_all(encode_frame(OPCODE_CLOSE, &status))
+                        .await
+                        .0?;
+                    return Ok(());
+                }
+                _ => return close(&mut client, CLOSE_PROTOCOL_ERROR).await,
+            }
+        }
+        let consumed = buf.len() - data.len();
+        buf.drain(..consumed);
+        client.report_pixels();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, encode_frame, parse_frame_header, unmask, OPCODE_TEXT};
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        for len in [0, 5, 125, 126, 0xFFFF, 0x10000] {
+            let payload = vec![b'x'; len];
+            let frame = encode_frame(OPCODE_TEXT, &payload);
+            let (header, header_len) = parse_frame_header(&frame).unwrap();
+            assert!(header.fin);
+            assert_eq!(header.opcode, OPCODE_TEXT);
+            assert_eq!(header.len, len as u64);
+            assert_eq!(&frame[header_len..], payload);
+            assert!(parse_frame_header(&frame[..header_len - 1]).is_none());
+        }
+
+        // A masked "Hello" from RFC 6455, section 5.7
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (header, header_len) = parse_frame_header(&frame).unwrap();
+        assert_eq!(header_len, 6);
+        let payload: Vec<u8> = unmask(&frame[header_len..], header.mask).collect();
+        assert_eq!(payload, b"Hello");
+    }
+}